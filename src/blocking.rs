@@ -0,0 +1,72 @@
+// blocking versions of the request functions, for callers without an async runtime
+// each call blocks on a runtime shared by this module, so none of these may be called from
+// inside a tokio runtime, where blocking on another one panics
+
+use std::{future::Future, sync::OnceLock};
+
+use async_sqlite::Client;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{CacheError, CacheStore, Lookup, Record, RequestOptions};
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    // one runtime for every call, so the shared HTTP client's pooled connections stay usable
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| Builder::new_current_thread().enable_all().build().unwrap())
+        .block_on(future)
+}
+
+pub fn create_connection(path: String) -> Result<Client, CacheError> {
+    block_on(crate::create_connection(path))
+}
+
+pub fn create_memory_connection() -> Result<Client, CacheError> {
+    block_on(crate::create_memory_connection())
+}
+
+pub fn request<S: CacheStore>(
+    connection: &S,
+    url: String,
+    method: String,
+    timeout: i64,
+    force_refresh: Option<bool>,
+    user_agent: Option<String>,
+) -> Result<Record, CacheError> {
+    block_on(crate::request(
+        connection,
+        url,
+        method,
+        timeout,
+        force_refresh,
+        user_agent,
+    ))
+}
+
+pub fn request_with_options<S: CacheStore>(
+    connection: &S,
+    url: String,
+    method: String,
+    timeout: i64,
+    options: RequestOptions,
+) -> Result<Record, CacheError> {
+    block_on(crate::request_with_options(
+        connection, url, method, timeout, options,
+    ))
+}
+
+pub fn get<S: CacheStore>(connection: &S, url: String, timeout: i64) -> Result<Record, CacheError> {
+    block_on(crate::get(connection, url, timeout))
+}
+
+pub fn lookup<S: CacheStore>(connection: &S, url: String, method: String) -> Lookup {
+    block_on(crate::lookup(connection, url, method))
+}
+
+pub fn get_if_cached<S: CacheStore>(connection: &S, url: String) -> Option<Record> {
+    block_on(crate::get_if_cached(connection, url))
+}
+
+pub fn get_cached<S: CacheStore>(connection: &S, url: String, method: String) -> Option<Record> {
+    block_on(crate::get_cached(connection, url, method))
+}
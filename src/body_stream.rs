@@ -0,0 +1,226 @@
+use std::{
+    future::Future,
+    io::Write,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_sqlite::{
+    rusqlite::{params, types::Type, DatabaseName, Error},
+    Client,
+};
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+
+use crate::{retry_busy, CacheError};
+
+// how much of a stored body each read takes from sqlite
+const CHUNK_LEN: usize = 64 * 1024;
+
+// where a stored record's body is, to read it without loading the rest of the row again
+#[derive(Debug, Clone)]
+pub(crate) struct StoredBody {
+    pub(crate) rowid: i64,
+    pub(crate) compressed: bool,
+    // kept once in blobs under its digest rather than in the row
+    pub(crate) in_blob: bool,
+    // a replaced row has a different digest, so a read never mixes two bodies
+    pub(crate) digest: Option<String>,
+}
+
+// a response body read as it's polled, from sqlite on a hit or the network on a miss
+pub struct BodyStream {
+    inner: Pin<Box<dyn Stream<Item = Result<Bytes, CacheError>> + Send>>,
+}
+
+impl BodyStream {
+    pub(crate) fn stored(connection: Client, table: String, body: StoredBody) -> Self {
+        // compressed bodies are decoded as they're read, so those are never held whole either
+        let reader = StoredReader {
+            connection,
+            table,
+            body,
+            offset: 0,
+            decoder: None,
+            done: false,
+        };
+        let chunks = stream::try_unfold(reader, |mut reader| async move {
+            Ok(reader.next_chunk().await?.map(|chunk| (chunk, reader)))
+        });
+        BodyStream {
+            inner: Box::pin(chunks),
+        }
+    }
+
+    pub(crate) fn loaded(body: Vec<u8>) -> Self {
+        // a body already held whole, as one chunk
+        let chunks = stream::iter((!body.is_empty()).then(|| Ok(Bytes::from(body))));
+        BodyStream {
+            inner: Box::pin(chunks),
+        }
+    }
+
+    pub(crate) fn fetched<F>(
+        response: reqwest::Response,
+        max_response_bytes: Option<usize>,
+        finish: F,
+    ) -> Self
+    where
+        F: FnOnce(Vec<u8>) -> Pin<Box<dyn Future<Output = Result<(), CacheError>> + Send>>
+            + Send
+            + 'static,
+    {
+        // pass each chunk on while keeping a copy, which finish gets once the body has been
+        // read to the end; a stream dropped before then stores nothing
+        let tee = Tee {
+            response: Some(response),
+            body: Vec::new(),
+            max_response_bytes,
+            finish: Some(finish),
+        };
+        let chunks = stream::try_unfold(tee, |mut tee| async move {
+            Ok(tee.next_chunk().await?.map(|chunk| (chunk, tee)))
+        });
+        BodyStream {
+            inner: Box::pin(chunks),
+        }
+    }
+
+    pub async fn bytes(mut self) -> Result<Vec<u8>, CacheError> {
+        // read the rest of the body into memory
+        let mut body = Vec::new();
+        while let Some(chunk) = self.next().await {
+            body.extend_from_slice(&chunk?);
+        }
+        Ok(body)
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = Result<Bytes, CacheError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+struct StoredReader {
+    connection: Client,
+    table: String,
+    body: StoredBody,
+    offset: usize,
+    decoder: Option<zstd::stream::write::Decoder<'static, Vec<u8>>>,
+    done: bool,
+}
+
+impl StoredReader {
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, CacheError> {
+        // read on until there's decoded output, as a chunk of zstd may not produce any
+        while !self.done {
+            let chunk = self.read_chunk().await?;
+            self.offset += chunk.len();
+            self.done = chunk.is_empty();
+            if !self.body.compressed {
+                if self.done {
+                    break;
+                }
+                return Ok(Some(Bytes::from(chunk)));
+            }
+            let decoder = match &mut self.decoder {
+                Some(decoder) => decoder,
+                None => self
+                    .decoder
+                    .insert(zstd::stream::write::Decoder::new(Vec::new()).map_err(decode_error)?),
+            };
+            if self.done {
+                decoder.flush().map_err(decode_error)?;
+            } else {
+                decoder.write_all(&chunk).map_err(decode_error)?;
+            }
+            let decoded = std::mem::take(decoder.get_mut());
+            if !decoded.is_empty() {
+                return Ok(Some(Bytes::from(decoded)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn read_chunk(&self) -> Result<Vec<u8>, CacheError> {
+        // the next CHUNK_LEN bytes of the stored body, empty at its end; a row replaced or
+        // deleted since the stream started is QueryReturnedNoRows rather than another body
+        let query = format!("SELECT digest FROM {} WHERE rowid = ?1;", self.table);
+        let chunk = retry_busy(|| {
+            let query = query.clone();
+            let table = self.table.clone();
+            let body = self.body.clone();
+            let offset = self.offset;
+            self.connection.conn(move |conn| {
+                let tx = conn.unchecked_transaction()?;
+                let digest: Option<String> =
+                    tx.query_row(&query, params![body.rowid], |row| row.get(0))?;
+                if digest != body.digest {
+                    return Err(Error::QueryReturnedNoRows);
+                }
+                let blob = if body.in_blob {
+                    let rowid: i64 = tx.query_row(
+                        "SELECT rowid FROM blobs WHERE hash = ?1;",
+                        params![digest],
+                        |row| row.get(0),
+                    )?;
+                    tx.blob_open(DatabaseName::Main, "blobs", "data", rowid, true)?
+                } else {
+                    tx.blob_open(
+                        DatabaseName::Main,
+                        &table,
+                        "response_bytes",
+                        body.rowid,
+                        true,
+                    )?
+                };
+                let mut chunk = vec![0; CHUNK_LEN.min(blob.len().saturating_sub(offset))];
+                blob.read_at_exact(&mut chunk, offset)?;
+                Ok(chunk)
+            })
+        });
+        Ok(chunk.await?)
+    }
+}
+
+fn decode_error(err: std::io::Error) -> CacheError {
+    // a stored body that isn't valid zstd, reported as record_from_row reports it
+    let err = Error::FromSqlConversionFailure(0, Type::Blob, err.into());
+    CacheError::Storage(async_sqlite::Error::Rusqlite(err))
+}
+
+struct Tee<F> {
+    // taken once the body has been read
+    response: Option<reqwest::Response>,
+    body: Vec<u8>,
+    max_response_bytes: Option<usize>,
+    finish: Option<F>,
+}
+
+impl<F> Tee<F>
+where
+    F: FnOnce(Vec<u8>) -> Pin<Box<dyn Future<Output = Result<(), CacheError>> + Send>>,
+{
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, CacheError> {
+        let Some(response) = &mut self.response else {
+            return Ok(None);
+        };
+        if let Some(chunk) = response.chunk().await? {
+            self.body.extend_from_slice(&chunk);
+            if let Some(max) = self.max_response_bytes.filter(|&max| self.body.len() > max) {
+                // nothing is stored and the rest of the body is never read
+                self.response = None;
+                return Err(CacheError::TooLarge(max));
+            }
+            return Ok(Some(chunk));
+        }
+        self.response = None;
+        if let Some(finish) = self.finish.take() {
+            finish(std::mem::take(&mut self.body)).await?;
+        }
+        Ok(None)
+    }
+}
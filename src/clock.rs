@@ -0,0 +1,49 @@
+use std::{
+    sync::atomic::{AtomicI64, Ordering},
+    time::Duration,
+};
+
+use crate::now_millis;
+
+// where the cache gets the time from, to decide expiry and stamp fetched records
+pub trait Clock: Send + Sync {
+    // milliseconds since the unix epoch
+    fn now_millis(&self) -> i64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        now_millis()
+    }
+}
+
+// a clock that only moves when told to, so expiry can be tested without sleeping
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now: AtomicI64,
+}
+
+impl MockClock {
+    pub fn new(now_millis: i64) -> Self {
+        MockClock {
+            now: AtomicI64::new(now_millis),
+        }
+    }
+
+    pub fn set(&self, now_millis: i64) {
+        self.now.store(now_millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_millis() as i64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> i64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
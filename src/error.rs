@@ -0,0 +1,122 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use reqwest::header::{InvalidHeaderName, InvalidHeaderValue};
+
+#[derive(Debug)]
+pub enum CacheError {
+    // the HTTP request failed
+    Http(reqwest::Error),
+    // the server didn't respond within the request timeout
+    Timeout(reqwest::Error),
+    // reading or writing the cache database failed
+    Storage(async_sqlite::Error),
+    // a header value couldn't be sent, e.g. it contained a newline
+    InvalidHeader(InvalidHeaderValue),
+    // a header name couldn't be sent, e.g. it contained a space
+    InvalidHeaderName(InvalidHeaderName),
+    // the method isn't a standard HTTP verb
+    InvalidMethod(String),
+    // the table name isn't limited to [A-Za-z0-9_], or is already used
+    InvalidTableName(String),
+    // the zstd compression level is outside the range zstd supports
+    InvalidCompressionLevel(i32),
+    // a request RequestCache shares between identical callers failed, for the caller that
+    // sent it and those that waited on it alike
+    Shared(Arc<CacheError>),
+    // CacheMode::OnlyIfCached found nothing stored for the request
+    NotCached,
+    // the response body was longer than max_response_bytes, which this holds
+    TooLarge(usize),
+    // a CacheStore couldn't keep a record but the response should still be served, as
+    // with lenient_storage; requests return the record with storage_failed set instead
+    NotStored(Box<CacheError>),
+    // a CacheStore other than sqlite failed
+    Store(Box<dyn std::error::Error + Send + Sync>),
+    // a root certificate file couldn't be read or held no PEM certificates
+    InvalidCertificate(PathBuf, Box<dyn std::error::Error + Send + Sync>),
+    // a JSON request body couldn't be serialized
+    #[cfg(feature = "json")]
+    Serialize(serde_json::Error),
+    // the response wasn't the JSON expected, with the body that was received
+    #[cfg(feature = "json")]
+    Deserialize(serde_json::Error, String),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Http(err) => write!(f, "request failed: {err}"),
+            CacheError::Timeout(err) => write!(f, "request timed out: {err}"),
+            CacheError::Storage(err) => write!(f, "cache storage failed: {err}"),
+            CacheError::InvalidHeader(err) => write!(f, "invalid header value: {err}"),
+            CacheError::InvalidHeaderName(err) => write!(f, "invalid header name: {err}"),
+            CacheError::InvalidMethod(method) => write!(f, "unsupported HTTP method: {method:?}"),
+            CacheError::InvalidTableName(name) => write!(f, "invalid table name: {name:?}"),
+            CacheError::InvalidCompressionLevel(level) => {
+                write!(f, "invalid zstd compression level: {level}")
+            }
+            CacheError::Shared(err) => write!(f, "shared request failed: {err}"),
+            CacheError::NotCached => write!(f, "no cached response for the request"),
+            CacheError::TooLarge(max) => write!(f, "response body larger than {max} bytes"),
+            CacheError::NotStored(err) => write!(f, "record not stored: {err}"),
+            CacheError::Store(err) => write!(f, "cache store failed: {err}"),
+            CacheError::InvalidCertificate(path, err) => {
+                write!(f, "invalid certificate file {}: {err}", path.display())
+            }
+            #[cfg(feature = "json")]
+            CacheError::Serialize(err) => write!(f, "couldn't serialize request body: {err}"),
+            #[cfg(feature = "json")]
+            CacheError::Deserialize(err, _) => write!(f, "couldn't deserialize response: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CacheError::Http(err) => Some(err),
+            CacheError::Timeout(err) => Some(err),
+            CacheError::Storage(err) => Some(err),
+            CacheError::InvalidHeader(err) => Some(err),
+            CacheError::InvalidHeaderName(err) => Some(err),
+            CacheError::InvalidMethod(_)
+            | CacheError::InvalidTableName(_)
+            | CacheError::InvalidCompressionLevel(_)
+            | CacheError::NotCached
+            | CacheError::TooLarge(_) => None,
+            CacheError::Shared(err) => Some(&**err),
+            CacheError::NotStored(err) => Some(&**err),
+            CacheError::Store(err) | CacheError::InvalidCertificate(_, err) => Some(&**err),
+            #[cfg(feature = "json")]
+            CacheError::Serialize(err) | CacheError::Deserialize(err, _) => Some(err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for CacheError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            CacheError::Timeout(err)
+        } else {
+            CacheError::Http(err)
+        }
+    }
+}
+
+impl From<async_sqlite::Error> for CacheError {
+    fn from(err: async_sqlite::Error) -> Self {
+        CacheError::Storage(err)
+    }
+}
+
+impl From<InvalidHeaderValue> for CacheError {
+    fn from(err: InvalidHeaderValue) -> Self {
+        CacheError::InvalidHeader(err)
+    }
+}
+
+impl From<InvalidHeaderName> for CacheError {
+    fn from(err: InvalidHeaderName) -> Self {
+        CacheError::InvalidHeaderName(err)
+    }
+}
@@ -1,254 +1,8260 @@
-use async_sqlite::{rusqlite::params, Client, ClientBuilder, Error};
-use reqwest::header::{HeaderMap, USER_AGENT};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::Read,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
 
-#[derive(Debug, Clone)]
+use async_sqlite::{
+    rusqlite::{
+        ffi, params, params_from_iter,
+        types::{Type, Value},
+        Connection, ErrorCode, OptionalExtension, Row,
+    },
+    Client, ClientBuilder, Error, JournalMode,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use body_stream::StoredBody;
+use futures_util::{stream, StreamExt};
+use rate_limit::RateLimiter;
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, DATE, ETAG,
+        EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION, USER_AGENT,
+    },
+    Method,
+};
+use sha2::{Digest, Sha256};
+
+// a tracing::debug! event with the tracing feature, nothing without it
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+// a tracing::warn! event with the tracing feature, nothing without it
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+    };
+}
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod body_stream;
+mod cache;
+mod clock;
+mod error;
+#[cfg(feature = "otel")]
+mod otel;
+pub mod prelude;
+mod rate_limit;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod store;
+
+pub use body_stream::BodyStream;
+pub use cache::{
+    CacheStats, RedirectPolicy, RequestCache, RequestCacheBuilder, RetryPolicy, SavingsReport,
+};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use error::CacheError;
+#[cfg(feature = "redis")]
+pub use redis_store::RedisStore;
+pub use store::{CacheStore, SqliteStore, StorageStrategy};
+
+// expires and fetched_at serialize as millisecond integers
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Record {
     pub request: String,
+    // where redirects ended up, the same as request when none were followed
+    pub final_url: String,
     pub method: String,
+    // the request body the record is keyed on, None when the request had none
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub body: Option<String>,
+    // the body as text, with any invalid UTF-8 replaced
     pub response: String,
+    // the body exactly as it was received
+    pub response_bytes: Vec<u8>,
+    // Content-Type response header, to decide how to decode response_bytes
+    pub content_type: Option<String>,
+    // HTTP status code of the response
+    pub status: u16,
+    // milliseconds since the unix epoch
     pub expires: i64,
-    pub cached: Option<bool>,
+    // true when read from the cache, false when fetched on this request
+    pub cached: bool,
+    // milliseconds since the unix epoch
+    pub fetched_at: i64,
+    // whether a fetched body differs from the one it replaced, None on cache hits
+    pub changed: Option<bool>,
+    // ETag response header, sent as If-None-Match to revalidate the record once it expires
+    pub etag: Option<String>,
+    // whether etag is weak (W/"..."), so a 304 can only confirm it by weak comparison
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub weak_etag: bool,
+    // Location response header, so a redirect that wasn't followed can be
+    pub location: Option<String>,
+    // every response header in the order received, repeated names like Set-Cookie kept apart
+    pub headers: Vec<(String, String)>,
+    // served from the cache after it expired
+    pub stale: bool,
+    // a stored record the server confirmed with a 304 on this request
+    pub revalidated: bool,
+    // the group the record was stored under, for invalidate_tag
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tag: Option<String>,
+    // milliseconds the server took to respond, retries included, None when nothing was
+    // fetched; only set on the returned record, never stored
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fetch_duration_ms: Option<u64>,
+    // the database failed, so a fetched record wasn't stored, which lenient_storage allows;
+    // only set on the returned record, never stored
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub storage_failed: bool,
+}
+
+impl Record {
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, CacheError> {
+        // parse the body as JSON, keeping the body in the error if it doesn't fit T
+        serde_json::from_slice(&self.response_bytes)
+            .map_err(|err| CacheError::Deserialize(err, self.response.clone()))
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        // the body exactly as it was received, e.g. to check a digest or signature over it
+        &self.response_bytes
+    }
+
+    pub fn text(&self) -> Option<String> {
+        // the body decoded from the charset its Content-Type declares, UTF-8 if it declares
+        // none, or None if it isn't valid in that charset rather than replacing what isn't
+        self.charset()
+            .decode_without_bom_handling_and_without_replacement(&self.response_bytes)
+            .map(Cow::into_owned)
+    }
+
+    pub fn decoded_text(&self) -> Option<String> {
+        // the body decompressed from its Content-Encoding, for one stored as the server
+        // compressed it by store_compressed, then decoded as text() does; None if an
+        // encoding isn't one of gzip, deflate, br or zstd, or the body doesn't decompress
+        let mut body = Cow::Borrowed(&self.response_bytes[..]);
+        let encodings = self.header("content-encoding").unwrap_or_default();
+        // applied in the order listed, so undone in reverse
+        for encoding in encodings.rsplit(',').map(str::trim) {
+            if !encoding.is_empty() && !encoding.eq_ignore_ascii_case("identity") {
+                body = Cow::Owned(decompress(encoding, &body)?);
+            }
+        }
+        self.charset()
+            .decode_without_bom_handling_and_without_replacement(&body)
+            .map(Cow::into_owned)
+    }
+
+    fn charset(&self) -> &'static encoding_rs::Encoding {
+        // an unknown charset falls back to UTF-8
+        let params = self.content_type.as_deref().unwrap_or_default().split(';');
+        params
+            .skip(1)
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+            .and_then(|(_, label)| {
+                encoding_rs::Encoding::for_label(label.trim().trim_matches('"').as_bytes())
+            })
+            .unwrap_or(encoding_rs::UTF_8)
+    }
+
+    pub fn decode(&self) -> Body<'_> {
+        // the body as its Content-Type describes it: text in the declared charset, UTF-8 if
+        // none is given, and bytes for other types; without a Content-Type, UTF-8 is text
+        let Some(content_type) = &self.content_type else {
+            return match std::str::from_utf8(&self.response_bytes) {
+                Ok(text) => Body::Text(Cow::Borrowed(text)),
+                Err(_) => Body::Bytes(&self.response_bytes),
+            };
+        };
+        let mut params = content_type.split(';').map(str::trim);
+        let essence = params.next().unwrap_or_default().to_ascii_lowercase();
+        let textual = essence.starts_with("text/")
+            || essence.ends_with("+json")
+            || essence.ends_with("+xml")
+            || matches!(
+                essence.as_str(),
+                "application/json"
+                    | "application/xml"
+                    | "application/javascript"
+                    | "application/x-www-form-urlencoded"
+            );
+        if !textual {
+            return Body::Bytes(&self.response_bytes);
+        }
+        // invalid sequences become U+FFFD
+        let decoded = self.charset().decode_with_bom_removal(&self.response_bytes);
+        Body::Text(decoded.0)
+    }
+
+    #[must_use]
+    pub fn was_cached(&self) -> bool {
+        // served from the cache rather than fetched, for code written against the old Option
+        self.cached
+    }
+
+    pub fn age(&self) -> Duration {
+        // how long ago the response was fetched
+        Duration::from_millis(now_millis().saturating_sub(self.fetched_at).max(0) as u64)
+    }
+
+    pub fn age_secs(&self) -> u64 {
+        // whole seconds since the response was fetched or last revalidated, for an Age header
+        self.age().as_secs()
+    }
+
+    pub fn ttl_remaining_secs(&self) -> u64 {
+        // whole seconds until the record expires, 0 once it has
+        (self.expires.saturating_sub(now_millis()).max(0) / 1000) as u64
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        // whether the record has expired at now, in milliseconds since the unix epoch, as the
+        // read path decides it: a record expiring this very millisecond isn't served
+        self.expires <= now
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        // whether the record would still be served from the cache now
+        !self.is_expired(now_millis())
+    }
+
+    pub fn seconds_until_expiry(&self) -> i64 {
+        // whole seconds until the record expires, rounded down, so negative once it has
+        self.expires.saturating_sub(now_millis()).div_euclid(1000)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        // the first value of a response header, names compared case-insensitively
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        // the media type of the Content-Type, e.g. "application/json", without parameters
+        // like charset; compare it case-insensitively
+        let content_type = self.content_type.as_deref()?;
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        (!media_type.is_empty()).then_some(media_type)
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        // the ETag's opaque tag, without quotes or a W/ prefix, which weak_etag records
+        let etag = self.etag.as_deref()?.trim();
+        let etag = etag.strip_prefix("W/").unwrap_or(etag);
+        Some(etag.trim_matches('"'))
+    }
+
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        // the Last-Modified date, None if it's missing or isn't an HTTP date
+        httpdate::parse_http_date(self.header("last-modified")?.trim()).ok()
+    }
+
+    pub fn cache_control(&self) -> CacheControl {
+        // the directives of every Cache-Control header, all unset when there's none;
+        // directives this doesn't know are skipped
+        let mut cache_control = CacheControl::default();
+        let directives = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("cache-control"))
+            .flat_map(|(_, value)| value.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = || Some(Duration::from_secs(value?.parse().ok()?));
+            match name.trim().to_ascii_lowercase().as_str() {
+                "max-age" => cache_control.max_age = seconds(),
+                "s-maxage" => cache_control.s_maxage = seconds(),
+                "stale-while-revalidate" => cache_control.stale_while_revalidate = seconds(),
+                "stale-if-error" => cache_control.stale_if_error = seconds(),
+                "no-cache" => cache_control.no_cache = true,
+                "no-store" => cache_control.no_store = true,
+                "private" => cache_control.private = true,
+                "public" => cache_control.public = true,
+                "must-revalidate" => cache_control.must_revalidate = true,
+                "immutable" => cache_control.immutable = true,
+                _ => {}
+            }
+        }
+        cache_control
+    }
+
+    pub fn freshness_lifetime(&self) -> Duration {
+        // how long the response stays fresh after it was fetched
+        Duration::from_millis(self.expires.saturating_sub(self.fetched_at).max(0) as u64)
+    }
+}
+
+fn decompress(encoding: &str, body: &[u8]) -> Option<Vec<u8>> {
+    // one Content-Encoding undone; deflate is zlib-wrapped, as HTTP means it
+    let mut decoded = Vec::new();
+    let read = match encoding.to_ascii_lowercase().as_str() {
+        "gzip" | "x-gzip" => flate2::read::GzDecoder::new(body).read_to_end(&mut decoded),
+        "deflate" => flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded),
+        "br" => brotli_decompressor::Decompressor::new(body, 4096).read_to_end(&mut decoded),
+        "zstd" => return zstd::decode_all(body).ok(),
+        _ => return None,
+    };
+    read.ok().map(|_| decoded)
+}
+
+// a response body as Record::decode reads it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body<'a> {
+    Text(Cow<'a, str>),
+    Bytes(&'a [u8]),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    // (request, method) of every record whose body doesn't match its digest
+    pub corrupt: Vec<(String, String)>,
+    pub deleted: usize,
+}
+
+// the directives of a response's Cache-Control, as Record::cache_control parses them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    pub max_age: Option<Duration>,
+    pub s_maxage: Option<Duration>,
+    pub stale_while_revalidate: Option<Duration>,
+    pub stale_if_error: Option<Duration>,
+    pub no_cache: bool,
+    pub no_store: bool,
+    // set by private alone or with field names, private="Set-Cookie"
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub immutable: bool,
+}
+
+// the disk a cache's database takes up, the -wal and -shm files WAL mode keeps beside it
+// included, as they can grow well past the database between checkpoints
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageInfo {
+    // as cache_size_bytes
+    pub database_bytes: u64,
+    // None when the file isn't there, as for a database in memory or not in WAL mode
+    pub wal_bytes: Option<u64>,
+    pub shm_bytes: Option<u64>,
+}
+
+impl StorageInfo {
+    pub fn total_bytes(&self) -> u64 {
+        let sidecars = self.wal_bytes.unwrap_or(0) + self.shm_bytes.unwrap_or(0);
+        self.database_bytes + sidecars
+    }
+}
+
+// which records purge_where deletes, every condition set must hold and none matches all
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PurgeCriteria {
+    host: Option<String>,
+    larger_than: Option<usize>,
+    older_than: Option<Duration>,
+    tag: Option<String>,
+}
+
+impl PurgeCriteria {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        // records whose url is on host, compared case-insensitively and ignoring the port
+        self.host = Some(host.into().to_ascii_lowercase());
+        self
+    }
+
+    pub fn larger_than(mut self, bytes: usize) -> Self {
+        // records whose body takes more than bytes as stored, after any compression
+        self.larger_than = Some(bytes);
+        self
+    }
+
+    pub fn older_than(mut self, age: Duration) -> Self {
+        // records fetched or last revalidated more than age ago
+        self.older_than = Some(age);
+        self
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        // records stored with tag
+        self.tag = Some(tag.into());
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WarmReport {
+    // (url, method) of every request fetched and stored
+    pub fetched: Vec<(String, String)>,
+    // already cached and unexpired, so not requested
+    pub skipped: Vec<(String, String)>,
+    pub failed: Vec<(String, String, CacheError)>,
+}
+
+// one request for request_many, as the corresponding arguments to request
+#[derive(Debug, Clone)]
+pub struct RequestSpec {
+    pub url: String,
+    pub method: String,
+    // seconds the response is cached for
+    pub timeout: i64,
+    pub body: Option<String>,
+    pub headers: Option<Vec<(String, String)>>,
+    // stored with the record, for invalidate_tag
+    pub tag: Option<String>,
+}
+
+impl RequestSpec {
+    pub fn new(url: impl Into<String>, timeout: i64) -> Self {
+        // a GET with no body or headers
+        RequestSpec {
+            url: url.into(),
+            method: "GET".to_string(),
+            timeout,
+            body: None,
+            headers: None,
+            tag: None,
+        }
+    }
 }
 
-pub async fn create_connection(path: String) -> Client {
+// the table records are kept in, unless a RequestCache is given another
+const DEFAULT_TABLE: &str = "requests";
+
+pub async fn create_connection(path: String) -> Result<Client, CacheError> {
     // Return a connection for the database located at /path
-    let client = ClientBuilder::new().path(path).open().await.unwrap();
-    let _ = client.conn(move |conn| conn.execute_batch("CREATE TABLE IF NOT EXISTS requests (request TEXT, method TEXT, response TEXT, expires INTEGER);")).await;
-    client
+    // it's opened in WAL mode, so readers don't wait on writers
+    // an error opening the file or setting up the schema is returned, not left for the
+    // first request to run into
+    create_connection_with_migration(path, |_| Ok(())).await
 }
 
-pub async fn request(
-    connection: &Client,
-    url: String,
-    method: String,
-    timeout: i64,
-    force_refresh: Option<bool>,
-    user_agent: Option<String>,
-) -> Record {
-    if force_refresh.unwrap_or(false) {
-        return make_request(connection, &url, &method, timeout, user_agent).await;
+pub async fn shared_connection(path: String) -> Result<Client, CacheError> {
+    // as create_connection, but each path is only opened once per process and later calls
+    // get a clone of that Client, which shares its sqlite connection
+    static CONNECTIONS: OnceLock<tokio::sync::Mutex<HashMap<String, Client>>> = OnceLock::new();
+    let connections = CONNECTIONS.get_or_init(Default::default);
+    // held while opening, so concurrent first calls for a path don't both open it
+    let mut connections = connections.lock().await;
+    if let Some(client) = connections.get(&path) {
+        return Ok(client.clone());
     }
-    // make a request, using cached response if one exists
-    match get_record(connection, url.clone(), method.clone()).await {
-        Some(x) => x,
-        _ => make_request(connection, &url, &method, timeout, user_agent).await,
+    // a path that fails to open isn't kept, so a later call tries again
+    let client = create_connection(path.clone()).await?;
+    connections.insert(path, client.clone());
+    Ok(client)
+}
+
+pub async fn create_connection_without_wal(path: String) -> Result<Client, CacheError> {
+    // as create_connection, keeping sqlite's rollback journal for filesystems where WAL
+    // doesn't work, such as network shares
+    Ok(try_create_connection(path, false, |_| Ok(())).await?)
+}
+
+pub async fn create_connection_with_migration(
+    path: String,
+    migration_hook: impl Fn(&Connection) -> Result<(), async_sqlite::rusqlite::Error> + Send + 'static,
+) -> Result<Client, CacheError> {
+    // as create_connection, then run migration_hook after the crate's own migrations
+    Ok(try_create_connection(path, true, migration_hook).await?)
+}
+
+pub async fn create_memory_connection() -> Result<Client, CacheError> {
+    // Return a connection for a database held in memory, which lasts as long as the Client
+    // the Client keeps a single sqlite connection open, so every call sees the same database
+    Ok(try_create_memory_connection().await?)
+}
+
+async fn try_create_memory_connection() -> Result<Client, Error> {
+    try_open_with_migration(ClientBuilder::new(), false, |_| Ok(())).await
+}
+
+async fn try_create_connection(
+    path: String,
+    wal: bool,
+    migration_hook: impl Fn(&Connection) -> Result<(), async_sqlite::rusqlite::Error> + Send + 'static,
+) -> Result<Client, Error> {
+    // open the database at path, returning a failed migration as well as a failed open
+    try_open_with_migration(ClientBuilder::new().path(path), wal, migration_hook).await
+}
+
+async fn try_open_with_migration(
+    builder: ClientBuilder,
+    wal: bool,
+    migration_hook: impl Fn(&Connection) -> Result<(), async_sqlite::rusqlite::Error> + Send + 'static,
+) -> Result<Client, Error> {
+    // open the database, create the table and run migrations, then migration_hook
+    // journal_mode persists in the file, so opening it again in WAL mode is a no-op
+    let builder = if wal {
+        builder.journal_mode(JournalMode::Wal)
+    } else {
+        builder
+    };
+    let client = builder.open().await?;
+    client
+        .conn(move |conn| {
+            // wait for other connections' locks before retry_busy has to step in
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            // WAL only needs syncing at checkpoints to stay consistent
+            if wal {
+                conn.pragma_update(None, "synchronous", "NORMAL")?;
+            }
+            check_integrity(conn)?;
+            conn.execute_batch("CREATE TABLE IF NOT EXISTS requests (request TEXT, method TEXT, response TEXT, expires INTEGER);")?;
+            migrate(conn)?;
+            migration_hook(conn)
+        })
+        .await?;
+    Ok(client)
+}
+
+fn check_integrity(conn: &Connection) -> Result<(), async_sqlite::rusqlite::Error> {
+    // a damaged file fails here when it's opened, rather than on some later read or write
+    // quick_check skips the index checks of integrity_check, so it stays cheap on big caches
+    let result: String = conn.query_row("PRAGMA quick_check(1);", [], |row| row.get(0))?;
+    if result == "ok" {
+        return Ok(());
     }
+    Err(async_sqlite::rusqlite::Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_CORRUPT),
+        Some(format!(
+            "cache database failed its integrity check: {result}"
+        )),
+    ))
 }
 
-async fn get_record(connection: &Client, url: String, method: String) -> Option<Record> {
-    // try to get a record from the DB
-    let current_time = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
-    let query = "SELECT * FROM requests WHERE request = ?1 AND method = ?2 AND expires > ?3 ORDER BY expires DESC LIMIT 1;";
+async fn tune_connection(
+    connection: &Client,
+    cache_size: Option<i64>,
+    mmap_size: Option<u64>,
+) -> Result<(), Error> {
+    // these only last as long as the connection, so every connection gets them
+    if cache_size.is_none() && mmap_size.is_none() {
+        return Ok(());
+    }
     connection
         .conn(move |conn| {
-            conn.query_row(query, params![url, method, current_time], |row| {
-                Ok(Record {
-                    method: row.get(0)?,
-                    request: row.get(1)?,
-                    response: row.get(2)?,
-                    expires: row.get(3)?,
-                    cached: Some(true),
-                })
-            })
+            if let Some(cache_size) = cache_size {
+                conn.pragma_update(None, "cache_size", cache_size)?;
+            }
+            if let Some(mmap_size) = mmap_size {
+                conn.pragma_update(None, "mmap_size", mmap_size as i64)?;
+            }
+            Ok(())
         })
         .await
-        .ok()
 }
 
-async fn insert_record(connection: &Client, record: Record) -> Result<usize, Error> {
-    // remove other records for this url/method
-    let method = record.method.clone();
-    let request = record.request.clone();
-    let query = "DELETE FROM requests WHERE request = ?1 AND method = ?2;";
-    let _ = connection
-        .conn(move |conn| conn.execute(&query, params![request, method]))
-        .await;
-    // then insert the new record
-    let query = "INSERT INTO requests VALUES (?1, ?2, ?3, ?4);";
+fn validate_table_name(name: &str) -> Result<(), CacheError> {
+    // table names are interpolated into SQL rather than bound, so only allow [A-Za-z0-9_],
+    // not starting with a digit, and not a name the crate or sqlite already uses
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.eq_ignore_ascii_case("settings")
+        && !name.eq_ignore_ascii_case("blobs")
+        && !name.eq_ignore_ascii_case("ranges")
+        && !name.to_ascii_lowercase().starts_with("sqlite_");
+    if valid {
+        Ok(())
+    } else {
+        Err(CacheError::InvalidTableName(name.to_string()))
+    }
+}
+
+async fn create_table(connection: &Client, table: &str) -> Result<(), Error> {
+    // tables other than the default have no older versions, so start with the current schema
+    // in the same column order the migrations give the default table
+    // user_version only tracks the default table, so new migrations must handle these too
+    let query = format!("CREATE TABLE IF NOT EXISTS {table} (request TEXT, method TEXT, response TEXT, expires INTEGER, fetched_at INTEGER, digest TEXT, status INTEGER NOT NULL DEFAULT 200, body TEXT NOT NULL DEFAULT '', etag TEXT, response_bytes BLOB, content_type TEXT, last_accessed INTEGER, location TEXT, headers TEXT, vary TEXT NOT NULL DEFAULT '', key_hash TEXT, final_url TEXT, compressed INTEGER NOT NULL DEFAULT 0); CREATE INDEX IF NOT EXISTS idx_{table}_lookup ON {table}(request, method, expires); CREATE INDEX IF NOT EXISTS idx_{table}_key ON {table}(key_hash);");
+    let table = table.to_string();
     connection
         .conn(move |conn| {
-            conn.execute(
-                query,
-                params![
-                    record.request,
-                    record.method,
-                    record.response,
-                    record.expires
-                ],
-            )
+            conn.execute_batch(&query)?;
+            add_unique_key(conn, &table)?;
+            add_tag_column(conn, &table)?;
+            add_blob_refs(conn, &table)?;
+            add_weak_etag_column(conn, &table)?;
+            add_pinned_column(conn, &table)?;
+            // the parts of responses fetched by range, for every table, until they're assembled
+            conn.execute_batch("CREATE TABLE IF NOT EXISTS ranges (cache_table TEXT NOT NULL, request TEXT NOT NULL, start INTEGER NOT NULL, total INTEGER NOT NULL, etag TEXT, data BLOB NOT NULL, PRIMARY KEY (cache_table, request, start));")
         })
         .await
 }
 
-async fn make_request(
-    connection: &Client,
-    url: &str,
-    method: &str,
-    timeout: i64,
-    user_agent: Option<String>,
-) -> Record {
-    // make an HTTP request and create a Record
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    if let Some(user_agent) = user_agent {
-        headers.insert(USER_AGENT, user_agent.parse().unwrap());
+fn add_tag_column(conn: &Connection, table: &str) -> Result<(), async_sqlite::rusqlite::Error> {
+    // records stored before tags existed have none
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = 'tag');",
+        params![table],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute_batch(&format!("SAVEPOINT tag_column; ALTER TABLE {table} ADD COLUMN tag TEXT; CREATE INDEX idx_{table}_tag ON {table}(tag); RELEASE tag_column;"))?;
+    }
+    Ok(())
+}
+
+fn add_weak_etag_column(
+    conn: &Connection,
+    table: &str,
+) -> Result<(), async_sqlite::rusqlite::Error> {
+    // records stored before the flag existed have it set from their etag
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = 'weak_etag');",
+        params![table],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute_batch(&format!("SAVEPOINT weak_etag_column; ALTER TABLE {table} ADD COLUMN weak_etag INTEGER NOT NULL DEFAULT 0; UPDATE {table} SET weak_etag = 1 WHERE etag LIKE 'W/%'; RELEASE weak_etag_column;"))?;
     }
+    Ok(())
+}
 
-    let response = client
-        .get(url)
-        .headers(headers)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+fn add_pinned_column(conn: &Connection, table: &str) -> Result<(), async_sqlite::rusqlite::Error> {
+    // records stored before pinning existed aren't pinned
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = 'pinned');",
+        params![table],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;"
+        ))?;
+    }
+    Ok(())
+}
+
+fn add_blob_refs(conn: &Connection, table: &str) -> Result<(), async_sqlite::rusqlite::Error> {
+    // bodies stored once in blobs, keyed by their digest, are referenced by rows with no
+    // response_bytes of their own; refs counts those rows across every table, kept by
+    // triggers so however a row is replaced or deleted, a blob goes with its last reference
+    conn.execute_batch(&format!("SAVEPOINT blob_refs; \
+        CREATE TABLE IF NOT EXISTS blobs (hash TEXT PRIMARY KEY, data BLOB NOT NULL, compressed INTEGER NOT NULL DEFAULT 0, refs INTEGER NOT NULL DEFAULT 0); \
+        CREATE TRIGGER IF NOT EXISTS {table}_blob_insert AFTER INSERT ON {table} WHEN new.response_bytes IS NULL BEGIN UPDATE blobs SET refs = refs + 1 WHERE hash = new.digest; END; \
+        CREATE TRIGGER IF NOT EXISTS {table}_blob_update AFTER UPDATE OF response_bytes, digest ON {table} BEGIN UPDATE blobs SET refs = refs + 1 WHERE hash = new.digest AND new.response_bytes IS NULL; UPDATE blobs SET refs = refs - 1 WHERE hash = old.digest AND old.response_bytes IS NULL; DELETE FROM blobs WHERE hash = old.digest AND refs <= 0; END; \
+        CREATE TRIGGER IF NOT EXISTS {table}_blob_delete AFTER DELETE ON {table} WHEN old.response_bytes IS NULL BEGIN UPDATE blobs SET refs = refs - 1 WHERE hash = old.digest; DELETE FROM blobs WHERE hash = old.digest AND refs <= 0; END; \
+        RELEASE blob_refs;"))
+}
+
+fn add_unique_key(conn: &Connection, table: &str) -> Result<(), async_sqlite::rusqlite::Error> {
+    // one row per request and variant, which insert_record upserts on; a table from before
+    // the key existed keeps only the newest of any duplicates
+    let index = format!("idx_{table}_unique");
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?1);",
+        params![index],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute_batch(&format!("SAVEPOINT unique_key; DELETE FROM {table} WHERE rowid NOT IN (SELECT MAX(rowid) FROM {table} GROUP BY request, method, body, vary); CREATE UNIQUE INDEX {index} ON {table}(request, method, body, vary); RELEASE unique_key;"))?;
+    }
+    Ok(())
+}
+
+fn migrate(conn: &Connection) -> Result<(), async_sqlite::rusqlite::Error> {
+    // bring a database written by an older version up to date
+    let version: i64 = conn.query_row("PRAGMA user_version;", [], |row| row.get(0))?;
+    if version < 1 {
+        // expires used to be stored in whole seconds
+        conn.execute_batch(
+            "BEGIN; UPDATE requests SET expires = expires * 1000; PRAGMA user_version = 1; COMMIT;",
+        )?;
+    }
+    if version < 2 {
+        // fetch times weren't recorded, so treat existing records as just fetched
+        conn.execute_batch(&format!(
+            "BEGIN; ALTER TABLE requests ADD COLUMN fetched_at INTEGER; UPDATE requests SET fetched_at = {}; PRAGMA user_version = 2; COMMIT;",
+            now_millis()
+        ))?;
+    }
+    if version < 3 {
+        // existing records have no digest and so always count as changed
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN digest TEXT; PRAGMA user_version = 3; COMMIT;",
+        )?;
+    }
+    if version < 4 {
+        conn.execute_batch(
+            "BEGIN; CREATE TABLE IF NOT EXISTS settings (name TEXT PRIMARY KEY, value INTEGER); PRAGMA user_version = 4; COMMIT;",
+        )?;
+    }
+    if version < 5 {
+        // statuses weren't recorded, so assume existing records were successful
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN status INTEGER NOT NULL DEFAULT 200; PRAGMA user_version = 5; COMMIT;",
+        )?;
+    }
+    if version < 6 {
+        // existing records were all requested without a body
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN body TEXT NOT NULL DEFAULT ''; PRAGMA user_version = 6; COMMIT;",
+        )?;
+    }
+    if version < 7 {
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN etag TEXT; PRAGMA user_version = 7; COMMIT;",
+        )?;
+    }
+    if version < 8 {
+        // existing records were all text, so their bytes are the UTF-8 encoding
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN response_bytes BLOB; ALTER TABLE requests ADD COLUMN content_type TEXT; UPDATE requests SET response_bytes = CAST(response AS BLOB); PRAGMA user_version = 8; COMMIT;",
+        )?;
+    }
+    if version < 9 {
+        // nothing was tracked, so treat records as last used when they were fetched
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN last_accessed INTEGER; UPDATE requests SET last_accessed = fetched_at; PRAGMA user_version = 9; COMMIT;",
+        )?;
+    }
+    if version < 10 {
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN location TEXT; PRAGMA user_version = 10; COMMIT;",
+        )?;
+    }
+    if version < 11 {
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN headers TEXT; PRAGMA user_version = 11; COMMIT;",
+        )?;
+    }
+    if version < 12 {
+        // existing records were stored without a Vary header, so they vary on nothing
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN vary TEXT NOT NULL DEFAULT ''; PRAGMA user_version = 12; COMMIT;",
+        )?;
+    }
+    if version < 13 {
+        // lookups filter on these, so they needn't scan the whole table
+        conn.execute_batch(
+            "BEGIN; CREATE INDEX IF NOT EXISTS idx_requests_lookup ON requests(request, method, expires); PRAGMA user_version = 13; COMMIT;",
+        )?;
+    }
+    if version < 14 {
+        // sqlite has no SHA-256, so hash the existing keys here
+        conn.execute_batch("BEGIN; ALTER TABLE requests ADD COLUMN key_hash TEXT;")?;
+        let keys = {
+            let mut stmt = conn.prepare("SELECT rowid, method, request, body FROM requests;")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for (rowid, method, request, body) in keys {
+            conn.execute(
+                "UPDATE requests SET key_hash = ?1 WHERE rowid = ?2;",
+                params![key_hash(&method, &request, &body), rowid],
+            )?;
+        }
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_requests_key ON requests(key_hash); PRAGMA user_version = 14; COMMIT;",
+        )?;
+    }
+    if version < 15 {
+        // records without one read back their request url
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN final_url TEXT; PRAGMA user_version = 15; COMMIT;",
+        )?;
+    }
+    if version < 16 {
+        // existing bodies were stored as they are
+        conn.execute_batch(
+            "BEGIN; ALTER TABLE requests ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0; PRAGMA user_version = 16; COMMIT;",
+        )?;
+    }
+    if version < 17 {
+        conn.execute_batch("BEGIN;")?;
+        add_unique_key(conn, DEFAULT_TABLE)?;
+        conn.execute_batch("PRAGMA user_version = 17; COMMIT;")?;
+    }
+    if version < 18 {
+        conn.execute_batch("BEGIN;")?;
+        add_tag_column(conn, DEFAULT_TABLE)?;
+        conn.execute_batch("PRAGMA user_version = 18; COMMIT;")?;
+    }
+    if version < 19 {
+        // the text is decoded from the bytes when read, so only the bytes are kept
+        conn.execute_batch(
+            "BEGIN; UPDATE requests SET response = NULL WHERE response_bytes IS NOT NULL; PRAGMA user_version = 19; COMMIT;",
+        )?;
+    }
+    if version < 20 {
+        // a row without response_bytes now references a blob, so rows from before bytes were
+        // kept have their text moved into them
+        conn.execute_batch("BEGIN; UPDATE requests SET response_bytes = CAST(response AS BLOB), response = NULL WHERE response_bytes IS NULL AND response IS NOT NULL;")?;
+        add_blob_refs(conn, DEFAULT_TABLE)?;
+        conn.execute_batch("PRAGMA user_version = 20; COMMIT;")?;
+    }
+    if version < 21 {
+        conn.execute_batch("BEGIN;")?;
+        add_weak_etag_column(conn, DEFAULT_TABLE)?;
+        conn.execute_batch("PRAGMA user_version = 21; COMMIT;")?;
+    }
+    if version < 22 {
+        conn.execute_batch("BEGIN;")?;
+        add_pinned_column(conn, DEFAULT_TABLE)?;
+        conn.execute_batch("PRAGMA user_version = 22; COMMIT;")?;
+    }
+    Ok(())
+}
 
-    // expires timeout seconds after now
-    let expiry_timestamp = std::time::SystemTime::now()
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
-        .as_secs() as i64
-        + timeout;
-    let record = Record {
-        request: url.to_string(),
-        method: method.to_string(),
-        response,
-        expires: expiry_timestamp,
-        cached: Some(false),
+        .as_millis() as i64
+}
+
+// what lookup found stored for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    // unexpired, as a request would serve it
+    Fresh(Record),
+    // expired or invalidated, with stale set; still there to revalidate or serve offline
+    Stale(Record),
+    Miss,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    // served an unexpired cached record
+    Hit,
+    // no usable cached record, so fetched and stored a new one
+    Miss,
+    // force_refresh fetched and stored a new record
+    Refresh,
+    // fetched but not stored, for unsafe methods, a timeout <= 0 or a skipped error status
+    Uncached,
+    // the fetch failed and stale_on_error served the stored record
+    Stale,
+    // an expired record was revalidated with its etag and served again
+    Revalidated,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    // use an unexpired record, otherwise fetch, revalidating an expired one if possible
+    #[default]
+    Default,
+    // always go to the server, revalidating a stored record if possible, and store the result
+    NoCache,
+    // fetch without looking in the cache or storing the response
+    NoStore,
+    // use whatever is stored however old, never the network; CacheError::NotCached on a miss
+    OnlyIfCached,
+    // as OnlyIfCached, but only an unexpired record is used
+    OnlyIfFresh,
+    // use whatever is stored however old without revalidating, fetch only on a miss
+    ForceCache,
+    // as Default, but an expired record is returned straight away while a spawned task
+    // refreshes it for the next caller; only RequestCache can spawn the refresh
+    StaleWhileRevalidate,
+    // as NoCache, but if the fetch fails the stored record is returned however old, with
+    // stale set, instead of the error
+    RefreshOrStale,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Freshness {
+    // records last the caller's ttl whatever the response headers say
+    #[default]
+    Ttl,
+    // Cache-Control and Expires set the lifetime when present, the ttl otherwise
+    PreferHeaders,
+    // only the response decides, as an HTTP cache would: Cache-Control and Expires, then a
+    // tenth of the time since Last-Modified, and a response with neither isn't stored
+    HeadersOnly,
+}
+
+impl Freshness {
+    fn from_use_cache_headers(use_cache_headers: Option<bool>) -> Self {
+        // the free functions' use_cache_headers flag
+        if use_cache_headers.unwrap_or(false) {
+            Freshness::PreferHeaders
+        } else {
+            Freshness::Ttl
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestOutcome {
+    pub record: Record,
+    pub status: CacheStatus,
+    pub from_network: bool,
+    pub elapsed: Duration,
+    // size of the response body
+    pub bytes: usize,
+}
+
+// the settings for request_with_options beyond url, method and timeout, each off or unset
+// by default, so new ones can be added without changing request's arguments
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    // fetch and store a fresh response even if an unexpired one is cached
+    pub force_refresh: bool,
+    pub user_agent: Option<String>,
+    // serve the stored record, however old, when the fetch fails
+    pub stale_on_error: bool,
+    // cache methods other than GET and HEAD
+    pub cache_unsafe: bool,
+    // don't store responses with a 4xx or 5xx status
+    pub skip_error_status: bool,
+    // sent with the request and keyed on, so different bodies are cached separately
+    pub body: Option<String>,
+    pub headers: Option<Vec<(String, String)>>,
+    // how long to wait for the server before giving up
+    pub request_timeout: Option<Duration>,
+    // let Cache-Control and Expires override timeout
+    pub use_cache_headers: bool,
+}
+
+pub async fn request<S: CacheStore>(
+    connection: &S,
+    url: String,
+    method: String,
+    timeout: i64,
+    force_refresh: Option<bool>,
+    user_agent: Option<String>,
+) -> Result<Record, CacheError> {
+    // anything further is set with request_with_options
+    let options = RequestOptions {
+        force_refresh: force_refresh.unwrap_or(false),
+        user_agent,
+        ..Default::default()
     };
-    // add to the cache
-    insert_record(connection, record.clone()).await.unwrap();
+    request_with_options(connection, url, method, timeout, options).await
+}
 
-    record
+pub async fn request_with_options<S: CacheStore>(
+    connection: &S,
+    url: String,
+    method: String,
+    timeout: i64,
+    options: RequestOptions,
+) -> Result<Record, CacheError> {
+    let params = FetchParams::from_options(url, method, timeout, options);
+    request_with_status(connection, http_client(), params, &SystemClock)
+        .await
+        .map(|(record, _)| record)
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{fs, thread::sleep, time::Duration};
+pub async fn get<S: CacheStore>(
+    connection: &S,
+    url: String,
+    timeout: i64,
+) -> Result<Record, CacheError> {
+    // a GET with request's defaults for everything else
+    request(connection, url, "GET".to_string(), timeout, None, None).await
+}
 
-    use super::*;
+pub async fn request_at(
+    connection: &Client,
+    url: String,
+    method: String,
+    timeout: i64,
+    now: Option<i64>,
+) -> Result<Record, CacheError> {
+    // as request with its defaults, but when now is given (milliseconds since the unix
+    // epoch) it's the time for both lookups and expiry, e.g. a simulation's logical clock
+    let clock = clock_at(now);
+    let store =
+        SqliteStore::with_table(connection.clone(), DEFAULT_TABLE.to_string(), clock.clone());
+    let params = FetchParams::from_options(url, method, timeout, RequestOptions::default());
+    request_with_status(&store, http_client(), params, &*clock)
+        .await
+        .map(|(record, _)| record)
+}
 
-    struct TestCleanup {
-        path: String,
+fn clock_at(now: Option<i64>) -> Arc<dyn Clock> {
+    // a clock stopped at now, or the system's without one
+    match now {
+        Some(now) => Arc::new(MockClock::new(now)),
+        None => Arc::new(SystemClock),
     }
+}
 
-    impl Drop for TestCleanup {
-        fn drop(&mut self) {
-            let _ = fs::remove_file(&self.path);
+pub async fn request_detailed<S: CacheStore>(
+    connection: &S,
+    url: String,
+    method: String,
+    timeout: i64,
+    options: RequestOptions,
+) -> Result<RequestOutcome, CacheError> {
+    // as request, but also report how the record was produced
+    let start = std::time::Instant::now();
+    let params = FetchParams::from_options(url, method, timeout, options);
+    let (record, status) =
+        request_with_status(connection, http_client(), params, &SystemClock).await?;
+    Ok(RequestOutcome {
+        bytes: record.response_bytes.len(),
+        record,
+        status,
+        from_network: matches!(
+            status,
+            CacheStatus::Miss
+                | CacheStatus::Refresh
+                | CacheStatus::Uncached
+                | CacheStatus::Revalidated
+        ),
+        elapsed: start.elapsed(),
+    })
+}
+
+// what request_with_status is asked to fetch and how, each field off or unset by default as
+// with the free functions; RequestCache fills in what its builder configured
+#[derive(Default)]
+struct FetchParams<'a> {
+    url: String,
+    // tried in turn after url fails, the response still cached under url
+    fallbacks: &'a [String],
+    method: String,
+    ttl_millis: i64,
+    force_refresh: bool,
+    user_agent: Option<String>,
+    stale_on_error: bool,
+    cache_unsafe: bool,
+    skip_error_status: bool,
+    body: Option<String>,
+    headers: Option<Vec<(String, String)>>,
+    request_timeout: Option<Duration>,
+    freshness: Freshness,
+    retry: Option<&'a RetryPolicy>,
+    negative_ttl_millis: Option<i64>,
+    mode: CacheMode,
+    max_response_bytes: Option<usize>,
+    rate_limiter: Option<&'a RateLimiter>,
+    should_cache: Option<&'a ShouldCacheFn>,
+    tag: Option<&'a str>,
+    expiry_jitter: Option<f64>,
+}
+
+impl FetchParams<'_> {
+    fn from_options(url: String, method: String, timeout: i64, options: RequestOptions) -> Self {
+        // the free functions' timeout is in seconds
+        FetchParams {
+            url,
+            method,
+            ttl_millis: timeout.saturating_mul(1000),
+            force_refresh: options.force_refresh,
+            user_agent: options.user_agent,
+            stale_on_error: options.stale_on_error,
+            cache_unsafe: options.cache_unsafe,
+            skip_error_status: options.skip_error_status,
+            body: options.body,
+            headers: options.headers,
+            request_timeout: options.request_timeout,
+            freshness: Freshness::from_use_cache_headers(Some(options.use_cache_headers)),
+            ..Default::default()
         }
     }
+}
 
-    #[tokio::test]
-    async fn test_create_connection() {
-        create_connection("test".to_string()).await;
-        let _ = fs::remove_file(&"test");
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "request",
+        skip_all,
+        fields(url = %params.url, method = %params.method)
+    )
+)]
+async fn request_with_status<S: CacheStore>(
+    connection: &S,
+    client: &reqwest::Client,
+    params: FetchParams<'_>,
+    clock: &dyn Clock,
+) -> Result<(Record, CacheStatus), CacheError> {
+    let FetchParams {
+        url,
+        fallbacks,
+        method,
+        ttl_millis,
+        force_refresh,
+        user_agent,
+        stale_on_error,
+        cache_unsafe,
+        skip_error_status,
+        body,
+        headers,
+        request_timeout,
+        freshness,
+        retry,
+        negative_ttl_millis,
+        mode,
+        max_response_bytes,
+        rate_limiter,
+        should_cache,
+        tag,
+        expiry_jitter,
+    } = params;
+    let method = parse_method(&method)?;
+    // a ttl of zero or less fetches a fresh response without caching it; ttls are in
+    // milliseconds here, so the builder can set ones shorter than a second
+    // only GET and HEAD are cached unless cache_unsafe opts other methods in
+    let cacheable = (is_safe_method(&method) || cache_unsafe) && mode != CacheMode::NoStore;
+    let ttl_millis = if cacheable { ttl_millis } else { 0 };
+    // requests with different bodies are cached separately, no body keys as ""
+    let key_body = body.clone().unwrap_or_default();
+    // responses with a Vary header only match requests with the same values for those headers
+    let sent_headers = request_headers(&user_agent, &headers);
+    if cacheable
+        && !force_refresh
+        && matches!(mode, CacheMode::OnlyIfCached | CacheMode::ForceCache)
+    {
+        if let Some(mut x) = connection
+            .get_stale_record(&url, method.as_str(), &key_body, &sent_headers)
+            .await
+        {
+            debug!(expires = x.expires, "cache hit, however old");
+            x.stale = x.is_expired(clock.now_millis());
+            let status = if x.stale {
+                CacheStatus::Stale
+            } else {
+                CacheStatus::Hit
+            };
+            return Ok((x, status));
+        }
     }
-
-    #[tokio::test]
-    async fn test_cache_request() {
-        let clean = TestCleanup {
-            path: "test_1".to_string(),
+    if mode == CacheMode::OnlyIfFresh && cacheable && !force_refresh {
+        if let Some(x) = connection
+            .get_record(&url, method.as_str(), &key_body, &sent_headers)
+            .await
+        {
+            debug!(expires = x.expires, "cache hit");
+            return Ok((x, CacheStatus::Hit));
+        }
+    }
+    if matches!(mode, CacheMode::OnlyIfCached | CacheMode::OnlyIfFresh) {
+        return Err(CacheError::NotCached);
+    }
+    let lookup = matches!(mode, CacheMode::Default | CacheMode::StaleWhileRevalidate);
+    if cacheable && !force_refresh && lookup {
+        // make a request, using cached response if one exists
+        if let Some(x) = connection
+            .get_record(&url, method.as_str(), &key_body, &sent_headers)
+            .await
+        {
+            debug!(expires = x.expires, "cache hit");
+            return Ok((x, CacheStatus::Hit));
+        }
+    }
+    if cacheable && !force_refresh && mode == CacheMode::StaleWhileRevalidate {
+        // the caller is told the record is stale, and refreshes it without waiting
+        if let Some(mut x) = connection
+            .get_stale_record(&url, method.as_str(), &key_body, &sent_headers)
+            .await
+        {
+            debug!(
+                expires = x.expires,
+                "serving stale record while revalidating"
+            );
+            x.stale = true;
+            return Ok((x, CacheStatus::Stale));
+        }
+    }
+    // an expired record with an etag can be revalidated instead of refetched
+    let stale = if cacheable && !force_refresh && mode != CacheMode::ForceCache {
+        get_record_for_revalidation(connection, &url, method.as_str(), &key_body, &sent_headers)
+            .await
+    } else {
+        None
+    };
+    match make_request(
+        connection,
+        client,
+        &url,
+        fallbacks,
+        &method,
+        body,
+        ttl_millis,
+        user_agent,
+        headers,
+        request_timeout,
+        freshness,
+        skip_error_status,
+        stale,
+        retry,
+        negative_ttl_millis,
+        max_response_bytes,
+        rate_limiter,
+        should_cache,
+        tag,
+        expiry_jitter,
+        clock,
+    )
+    .await
+    {
+        Ok(record) => {
+            // only stored records have a changed flag
+            let status = if record.changed.is_none() {
+                CacheStatus::Uncached
+            } else if record.cached {
+                CacheStatus::Revalidated
+            } else if force_refresh {
+                CacheStatus::Refresh
+            } else {
+                CacheStatus::Miss
+            };
+            debug!(?status, expires = record.expires, "cache miss");
+            Ok((record, status))
+        }
+        Err(err) => {
+            debug!(error = %err, "request failed");
+            // fall back to whatever is stored, however old, if the fetch failed
+            if stale_on_error || mode == CacheMode::RefreshOrStale {
+                if let Some(mut x) = connection
+                    .get_stale_record(&url, method.as_str(), &key_body, &sent_headers)
+                    .await
+                {
+                    debug!(expires = x.expires, "serving stale record");
+                    x.stale = true;
+                    return Ok((x, CacheStatus::Stale));
+                }
+            }
+            Err(err)
+        }
+    }
+}
+
+pub async fn warm<S: CacheStore>(
+    connection: &S,
+    urls: Vec<(String, String)>,
+    timeout: i64,
+    user_agent: Option<String>,
+    concurrency: usize,
+) -> WarmReport {
+    // fetch and store each (url, method) ahead of time, at most concurrency at once;
+    // requests already cached and unexpired are skipped, and a failure doesn't stop the rest
+    let results = stream::iter(urls)
+        .map(|(url, method)| {
+            let user_agent = user_agent.clone();
+            async move {
+                let options = RequestOptions {
+                    user_agent,
+                    ..Default::default()
+                };
+                let params =
+                    FetchParams::from_options(url.clone(), method.clone(), timeout, options);
+                let result =
+                    request_with_status(connection, http_client(), params, &SystemClock).await;
+                (url, method, result)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let mut report = WarmReport::default();
+    for (url, method, result) in results {
+        match result {
+            Ok((_, CacheStatus::Hit)) => report.skipped.push((url, method)),
+            Ok(_) => report.fetched.push((url, method)),
+            Err(err) => report.failed.push((url, method, err)),
+        }
+    }
+    report
+}
+
+pub async fn request_many<S: CacheStore>(
+    connection: &S,
+    reqs: Vec<RequestSpec>,
+    concurrency: usize,
+) -> Vec<Result<Record, CacheError>> {
+    // run each request as request would, returning their results in the order given;
+    // at most concurrency are fetched at once, and ones already cached never wait for that
+    let network = tokio::sync::Semaphore::new(concurrency.max(1));
+    let results = reqs.into_iter().map(|spec| {
+        let network = &network;
+        async move {
+            let method = parse_method(&spec.method)?;
+            let sent_headers = request_headers(&None, &spec.headers);
+            let key_body = spec.body.clone().unwrap_or_default();
+            if let Some(record) = connection
+                .get_record(&spec.url, method.as_str(), &key_body, &sent_headers)
+                .await
+            {
+                return Ok(record);
+            }
+            // the semaphore is never closed
+            let _slot = network.acquire().await.unwrap();
+            let options = RequestOptions {
+                body: spec.body,
+                headers: spec.headers,
+                ..Default::default()
+            };
+            let params = FetchParams {
+                tag: spec.tag.as_deref(),
+                ..FetchParams::from_options(spec.url, spec.method, spec.timeout, options)
+            };
+            request_with_status(connection, http_client(), params, &SystemClock)
+                .await
+                .map(|(record, _)| record)
+        }
+    });
+    futures_util::future::join_all(results).await
+}
+
+pub async fn lookup<S: CacheStore>(connection: &S, url: String, method: String) -> Lookup {
+    // as get_cached, telling a record that has expired apart from none at all
+    let Ok(method) = parse_method(&method) else {
+        return Lookup::Miss;
+    };
+    if let Some(record) = connection.get_record(&url, method.as_str(), "", &[]).await {
+        return Lookup::Fresh(record);
+    }
+    match connection
+        .get_stale_record(&url, method.as_str(), "", &[])
+        .await
+    {
+        Some(record) => Lookup::Stale(Record {
+            stale: true,
+            ..record
+        }),
+        None => Lookup::Miss,
+    }
+}
+
+pub async fn get_cached_at(
+    connection: &Client,
+    url: String,
+    method: String,
+    now: Option<i64>,
+) -> Option<Record> {
+    // as get_cached, unexpired as of now when it's given, as with request_at
+    let store =
+        SqliteStore::with_table(connection.clone(), DEFAULT_TABLE.to_string(), clock_at(now));
+    get_cached(&store, url, method).await
+}
+
+pub async fn get_if_cached<S: CacheStore>(connection: &S, url: String) -> Option<Record> {
+    // as get_cached for a GET
+    get_cached(connection, url, "GET".to_string()).await
+}
+
+fn normalize_method(method: &str) -> String {
+    // " get " and "GET" are the same method, so they key the same records
+    method.trim().to_ascii_uppercase()
+}
+
+fn parse_method(method: &str) -> Result<Method, CacheError> {
+    // normalise the method so that " get " and "GET" share a cache entry
+    let normalised = normalize_method(method);
+    match normalised.as_str() {
+        "GET" | "HEAD" | "POST" | "PUT" | "DELETE" | "PATCH" | "OPTIONS" | "TRACE" | "CONNECT" => {
+            Method::from_bytes(normalised.as_bytes())
+                .map_err(|_| CacheError::InvalidMethod(method.to_string()))
+        }
+        _ => Err(CacheError::InvalidMethod(method.to_string())),
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    // safe methods don't change server state, so their responses can be reused
+    matches!(*method, Method::GET | Method::HEAD)
+}
+
+pub async fn get_cached<S: CacheStore>(
+    connection: &S,
+    url: String,
+    method: String,
+) -> Option<Record> {
+    // look up an unexpired record without ever making a request, None on a miss
+    let method = parse_method(&method).ok()?;
+    connection.get_record(&url, method.as_str(), "", &[]).await
+}
+
+async fn get_record(
+    connection: &Client,
+    table: &str,
+    url: String,
+    method: String,
+    body: String,
+    request_headers: &[(String, String)],
+    now: i64,
+) -> Option<Record> {
+    // try to get a record from the DB unexpired as of now, of the variant the request's headers select
+    let found = get_record_with(
+        connection,
+        table,
+        url,
+        method,
+        body,
+        request_headers,
+        now,
+        RECORD_COLUMNS,
+    );
+    found.await.map(|(record, _)| record)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn get_record_with(
+    connection: &Client,
+    table: &str,
+    url: String,
+    method: String,
+    body: String,
+    request_headers: &[(String, String)],
+    now: i64,
+    columns: &'static str,
+) -> Option<(Record, StoredBody)> {
+    // as get_record, selecting columns, with where the body is stored
+    // normalized once, so the hit is noted on the row the lookup found
+    let method = normalize_method(&method);
+    let (record, vary, stored) = query_record(
+        connection,
+        table,
+        url.clone(),
+        method.clone(),
+        body.clone(),
+        Some(now),
+        request_headers,
+        columns,
+    )
+    .await?;
+    // with access tracking on, note the hit so eviction drops the least recently used first;
+    // when it's off the settings check matches nothing and no write happens
+    let query = format!("UPDATE {table} SET last_accessed = ?4 WHERE key_hash = ?7 AND request = ?1 AND method = ?2 AND body = ?3 AND vary = ?6 AND EXISTS (SELECT 1 FROM settings WHERE name = ?5 AND value = 1);");
+    let setting = setting_name(table, "track_access");
+    let hash = key_hash(&method, &url, &body);
+    let _ = retry_busy(|| {
+        let query = query.clone();
+        let url = url.clone();
+        let method = method.clone();
+        let body = body.clone();
+        let setting = setting.clone();
+        let vary = vary.clone();
+        let hash = hash.clone();
+        connection.conn(move |conn| {
+            let params = params![url, method, body, now, setting, vary, hash];
+            conn.execute(&query, params)
+        })
+    })
+    .await;
+    Some((record, stored))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn query_record(
+    connection: &Client,
+    table: &str,
+    url: String,
+    method: String,
+    body: String,
+    fresh_at: Option<i64>,
+    request_headers: &[(String, String)],
+    columns: &'static str,
+) -> Option<(Record, String, StoredBody)> {
+    // try to get a record from the DB, with fresh_at it must be unexpired at that time
+    // and fetched no earlier than the invalidation epoch
+    // rows are found by key_hash, the full key is still compared in case of a collision
+    let query = if fresh_at.is_some() {
+        format!("SELECT {columns}, vary, rowid, compressed AS stored_compressed, {table}.response_bytes IS NULL AS in_blob, digest FROM {table} WHERE key_hash = ?6 AND request = ?1 AND method = ?2 AND body = ?3 AND expires > ?4 AND fetched_at >= (SELECT COALESCE(MAX(value), 0) FROM settings WHERE name = ?5) ORDER BY expires DESC;")
+    } else {
+        format!("SELECT {columns}, vary, rowid, compressed AS stored_compressed, {table}.response_bytes IS NULL AS in_blob, digest FROM {table} WHERE key_hash = ?4 AND request = ?1 AND method = ?2 AND body = ?3 ORDER BY expires DESC;")
+    };
+    let epoch = setting_name(table, "invalidation_epoch");
+    let method = normalize_method(&method);
+    let hash = key_hash(&method, &url, &body);
+    let rows = retry_busy(|| {
+        let query = query.clone();
+        let url = url.clone();
+        let method = method.clone();
+        let body = body.clone();
+        let epoch = epoch.clone();
+        let hash = hash.clone();
+        connection.conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let row = |row: &Row| {
+                let stored = StoredBody {
+                    rowid: row.get("rowid")?,
+                    compressed: row.get("stored_compressed")?,
+                    in_blob: row.get("in_blob")?,
+                    digest: row.get("digest")?,
+                };
+                Ok((record_from_row(row)?, row.get::<_, String>("vary")?, stored))
+            };
+            let rows = if let Some(now) = fresh_at {
+                let params = params![url, method, body, now, epoch, hash];
+                stmt.query_map(params, row)?.collect::<Result<Vec<_>, _>>()
+            } else {
+                stmt.query_map(params![url, method, body, hash], row)?
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            rows
+        })
+    })
+    .await
+    .ok()?;
+    // each variant of the response is a row, use the newest one this request selects
+    rows.into_iter().find(|(record, vary, _)| {
+        vary_key(&record.headers, request_headers).as_deref() == Some(vary.as_str())
+    })
+}
+
+// the columns record_from_row reads, selected by name so the table's column order doesn't matter
+// a deduplicated body is read from blobs
+const RECORD_COLUMNS: &str =
+    "request, method, body, response, COALESCE(response_bytes, (SELECT data FROM blobs WHERE hash = digest)) AS response_bytes, content_type, status, expires, fetched_at, etag, weak_etag, location, headers, final_url, compressed, tag";
+// as RECORD_COLUMNS with an empty body, for records whose body is streamed instead
+const STREAMED_COLUMNS: &str =
+    "request, method, body, '' AS response, X'' AS response_bytes, content_type, status, expires, fetched_at, etag, weak_etag, location, headers, final_url, 0 AS compressed, tag";
+
+fn record_from_row(row: &Row) -> Result<Record, async_sqlite::rusqlite::Error> {
+    // build a cached Record from a row selecting RECORD_COLUMNS
+    let request: String = row.get("request")?;
+    let final_url: Option<String> = row.get("final_url")?;
+    // rows only keep the body's bytes, zstd compressed or not, and the text is decoded from
+    // them; a row written without bytes only has the text
+    let response_bytes = if row.get("compressed")? {
+        let compressed: Vec<u8> = row.get("response_bytes")?;
+        zstd::decode_all(&compressed[..]).map_err(|err| {
+            let column = row
+                .as_ref()
+                .column_index("response_bytes")
+                .unwrap_or_default();
+            async_sqlite::rusqlite::Error::FromSqlConversionFailure(column, Type::Blob, err.into())
+        })?
+    } else {
+        match row.get::<_, Option<Vec<u8>>>("response_bytes")? {
+            Some(bytes) => bytes,
+            None => row.get::<_, String>("response")?.into_bytes(),
+        }
+    };
+    let response = String::from_utf8_lossy(&response_bytes).into_owned();
+    Ok(Record {
+        final_url: final_url.unwrap_or_else(|| request.clone()),
+        request,
+        method: row.get("method")?,
+        body: Some(row.get::<_, String>("body")?).filter(|body| !body.is_empty()),
+        response,
+        response_bytes,
+        content_type: row.get("content_type")?,
+        status: row.get("status")?,
+        expires: row.get("expires")?,
+        cached: true,
+        fetched_at: row.get("fetched_at")?,
+        changed: None,
+        etag: row.get("etag")?,
+        weak_etag: row.get("weak_etag")?,
+        location: row.get("location")?,
+        headers: decode_headers(row.get("headers")?),
+        stale: false,
+        revalidated: false,
+        tag: row.get("tag")?,
+        fetch_duration_ms: None,
+        storage_failed: false,
+    })
+}
+
+fn encode_headers(headers: &[(String, String)]) -> String {
+    // one "name: value" line per header, neither can contain a newline
+    headers
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}\n"))
+        .collect()
+}
+
+fn request_headers(
+    user_agent: &Option<String>,
+    headers: &Option<Vec<(String, String)>>,
+) -> Vec<(String, String)> {
+    // the headers a request is sent with, as far as Vary is concerned
+    let user_agent = user_agent
+        .iter()
+        .map(|user_agent| (USER_AGENT.to_string(), user_agent.clone()));
+    user_agent
+        .chain(headers.iter().flatten().cloned())
+        .collect()
+}
+
+pub fn normalize_url(url: &str, drop_params: &[String]) -> String {
+    // one spelling of a url for every way of writing it: parsing lowercases the host and
+    // drops a default port, then query params are sorted by name and any matching
+    // drop_params removed, where "utm_*" matches by prefix; urls that don't parse are kept
+    filter_query(url, |name| !matches_param(name, drop_params))
+}
+
+pub(crate) fn significant_url(url: &str, significant: &[String]) -> String {
+    // as normalize_url, keeping only the params matching significant
+    filter_query(url, |name| matches_param(name, significant))
+}
+
+fn matches_param(name: &str, params: &[String]) -> bool {
+    params.iter().any(|param| match param.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == param,
+    })
+}
+
+fn filter_query(url: &str, keep: impl Fn(&str) -> bool) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return url.to_string();
+    };
+    let mut params: Vec<(String, String)> = parsed
+        .query_pairs()
+        .into_owned()
+        .filter(|(name, _)| keep(name))
+        .collect();
+    // a stable sort, so repeated params keep their order
+    params.sort_by(|(a, _), (b, _)| a.cmp(b));
+    if params.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(params);
+    }
+    parsed.to_string()
+}
+
+pub fn with_query<K: AsRef<str>, V: AsRef<str>>(url: &str, query: &[(K, V)]) -> String {
+    // url with query appended, form encoded as reqwest's query does; the url is otherwise
+    // left as written, so the result keys the same record as the same url written out
+    if query.is_empty() {
+        return url.to_string();
+    }
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (url, None),
+    };
+    let encoded = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(query)
+        .finish();
+    let separator = match url.split_once('?') {
+        Some((_, "")) => "",
+        Some(_) => "&",
+        None => "?",
+    };
+    let mut appended = format!("{url}{separator}{encoded}");
+    if let Some(fragment) = fragment {
+        appended = format!("{appended}#{fragment}");
+    }
+    appended
+}
+
+pub fn basic_auth(username: &str, password: &str) -> (String, String) {
+    // an Authorization header for the headers parameter, the credentials base64 encoded
+    let credentials = BASE64.encode(format!("{username}:{password}"));
+    (AUTHORIZATION.to_string(), format!("Basic {credentials}"))
+}
+
+pub fn bearer_auth(token: &str) -> (String, String) {
+    // an Authorization header for the headers parameter
+    (AUTHORIZATION.to_string(), format!("Bearer {token}"))
+}
+
+fn key_body(body: &str, request_headers: &[(String, String)], auth_in_key: bool) -> String {
+    // the body a request is keyed on; when responses differ per user the Authorization header
+    // is keyed on too, as a digest so the credentials themselves aren't stored
+    let auth = request_headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(AUTHORIZATION.as_str()));
+    match auth {
+        Some((_, value)) if auth_in_key => format!("{body}\n{}", body_digest(value.as_bytes())),
+        _ => body.to_string(),
+    }
+}
+
+#[cfg_attr(not(feature = "json"), allow(unused_variables))]
+fn canonical_body(body: &str, canonical_json: bool) -> Cow<'_, str> {
+    // with canonical_json a JSON body is keyed with its keys sorted and whitespace dropped, so
+    // equivalent bodies share a record; a body that isn't JSON is keyed as sent
+    #[cfg(feature = "json")]
+    if canonical_json {
+        if let Ok(value) = serde_json::from_str(body) {
+            return Cow::Owned(sorted_json(value).to_string());
+        }
+    }
+    Cow::Borrowed(body)
+}
+
+#[cfg(feature = "json")]
+fn sorted_json(value: serde_json::Value) -> serde_json::Value {
+    // serde_json only keeps object keys sorted itself without its preserve_order feature
+    use serde_json::Value;
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let entries = entries.into_iter();
+            Value::Object(
+                entries
+                    .map(|(key, value)| (key, sorted_json(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sorted_json).collect()),
+        value => value,
+    }
+}
+
+fn vary_key(
+    response_headers: &[(String, String)],
+    request_headers: &[(String, String)],
+) -> Option<String> {
+    // the request's values of every header the response's Vary names, one line each in name
+    // order; None for Vary: *, which no later request can match
+    let mut names: Vec<String> = response_headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("vary"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.iter().any(|name| name == "*") {
+        return None;
+    }
+    names.sort();
+    names.dedup();
+    let key = names
+        .iter()
+        .map(|name| {
+            let values: Vec<_> = request_headers
+                .iter()
+                .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+                .collect();
+            format!("{name}: {}\n", values.join(", "))
+        })
+        .collect();
+    Some(key)
+}
+
+fn decode_headers(headers: Option<String>) -> Vec<(String, String)> {
+    // records stored before headers were kept have none
+    headers
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+async fn get_record_for_revalidation<S: CacheStore>(
+    connection: &S,
+    url: &str,
+    method: &str,
+    body: &str,
+    request_headers: &[(String, String)],
+) -> Option<Record> {
+    // get a record from the store, however old, if it can be revalidated, which takes an
+    // ETag or a Last-Modified header
+    connection
+        .get_stale_record(url, method, body, request_headers)
+        .await
+        .filter(|record| record.etag.is_some() || record.header(LAST_MODIFIED.as_str()).is_some())
+}
+
+async fn insert_record(
+    connection: &Client,
+    table: &str,
+    record: Record,
+    body: &str,
+    request_headers: &[(String, String)],
+) -> Result<bool, Error> {
+    // store a record for a request body, returning whether the response differs from the stored one
+    let inserted = insert_record_counted(connection, table, record, body, request_headers);
+    Ok(inserted.await?.0)
+}
+
+async fn insert_record_counted(
+    connection: &Client,
+    table: &str,
+    record: Record,
+    body: &str,
+    request_headers: &[(String, String)],
+) -> Result<(bool, i64), Error> {
+    // as insert_record, also returning how the number of rows changed, a new row adding one
+    // and each one it evicts taking one away
+    // only the variant selected by the request's headers is replaced
+    let record = Record {
+        method: normalize_method(&record.method),
+        ..record
+    };
+    let method = record.method.clone();
+    let request = record.request.clone();
+    let body = body.to_string();
+    let vary = vary_key(&record.headers, request_headers).unwrap_or_else(|| "*".to_string());
+    let hash = key_hash(&method, &request, &body);
+    let digest = body_digest(&record.response_bytes);
+    // compare digests first so an unchanged body is never rewritten
+    let query = format!("SELECT digest FROM {table} WHERE key_hash = ?5 AND request = ?1 AND method = ?2 AND body = ?3 AND vary = ?4;");
+    let stored = retry_busy(|| {
+        let query = query.clone();
+        let request = request.clone();
+        let method = method.clone();
+        let body = body.clone();
+        let vary = vary.clone();
+        let hash = hash.clone();
+        connection.conn(move |conn| {
+            conn.query_row(&query, params![request, method, body, vary, hash], |row| {
+                row.get::<_, Option<String>>(0)
+            })
+            .optional()
+        })
+    })
+    .await?
+    .flatten();
+    if stored.as_ref() == Some(&digest) {
+        let query = format!("UPDATE {table} SET expires = ?4, fetched_at = ?5, last_accessed = ?5, status = ?6, etag = ?7, headers = ?8, final_url = ?11, tag = COALESCE(?12, tag), weak_etag = ?13 WHERE key_hash = ?10 AND request = ?1 AND method = ?2 AND body = ?3 AND vary = ?9;");
+        let headers = encode_headers(&record.headers);
+        retry_busy(|| {
+            let query = query.clone();
+            let request = request.clone();
+            let method = method.clone();
+            let body = body.clone();
+            let etag = record.etag.clone();
+            let headers = headers.clone();
+            let vary = vary.clone();
+            let hash = hash.clone();
+            let final_url = record.final_url.clone();
+            let tag = record.tag.clone();
+            connection.conn(move |conn| {
+                conn.execute(
+                    &query,
+                    params![
+                        request,
+                        method,
+                        body,
+                        record.expires,
+                        record.fetched_at,
+                        record.status,
+                        etag,
+                        headers,
+                        vary,
+                        hash,
+                        final_url,
+                        tag,
+                        record.weak_etag
+                    ],
+                )
+            })
+        })
+        .await?;
+        return Ok((false, 0));
+    }
+    // replace the record for this url/method/body and variant in one statement, so a
+    // concurrent lookup sees either the old record or the new one, never neither
+    let query = format!("INSERT INTO {table} (request, method, response, expires, fetched_at, last_accessed, digest, status, body, etag, response_bytes, content_type, location, headers, vary, key_hash, final_url, compressed, tag, weak_etag) VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19) ON CONFLICT (request, method, body, vary) DO UPDATE SET response = excluded.response, expires = excluded.expires, fetched_at = excluded.fetched_at, last_accessed = excluded.last_accessed, digest = excluded.digest, status = excluded.status, etag = excluded.etag, weak_etag = excluded.weak_etag, response_bytes = excluded.response_bytes, content_type = excluded.content_type, location = excluded.location, headers = excluded.headers, key_hash = excluded.key_hash, final_url = excluded.final_url, compressed = excluded.compressed, tag = COALESCE(excluded.tag, tag);");
+    let exists = format!("SELECT EXISTS (SELECT 1 FROM {table} WHERE request = ?1 AND method = ?2 AND body = ?3 AND vary = ?4);");
+    let evict = evict_query(table);
+    let max_entries = setting_name(table, "max_entries");
+    let compression = setting_name(table, "compression");
+    let compression_level = setting_name(table, "compression_level");
+    let deduplication = setting_name(table, "deduplication");
+    let added = retry_busy(|| {
+        let query = query.clone();
+        let exists = exists.clone();
+        let evict = evict.clone();
+        let max_entries = max_entries.clone();
+        let compression = compression.clone();
+        let compression_level = compression_level.clone();
+        let deduplication = deduplication.clone();
+        let record = record.clone();
+        let digest = digest.clone();
+        let body = body.clone();
+        let vary = vary.clone();
+        let hash = hash.clone();
+        connection.conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let enabled = |setting: &str| {
+                tx.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM settings WHERE name = ?1 AND value = 1);",
+                    params![setting],
+                    |row| row.get::<_, bool>(0),
+                )
+            };
+            // with compression on only the zstd body is kept, the text is decoded from it
+            let compress = enabled(&compression)?;
+            // with deduplication on the body is kept in blobs, once however many rows have
+            // it, in whichever form it was first stored
+            let dedupe = enabled(&deduplication)?;
+            let blob: Option<bool> = if dedupe {
+                let query = "SELECT compressed FROM blobs WHERE hash = ?1;";
+                tx.query_row(query, params![digest], |row| row.get(0))
+                    .optional()?
+            } else {
+                None
+            };
+            let compress = blob.unwrap_or(compress);
+            // only the bytes are stored, the text is decoded from them when read
+            let mut response_bytes = match blob {
+                Some(_) => None,
+                None if compress => {
+                    // level 0 is zstd's default
+                    let level: i32 = tx.query_row(
+                        "SELECT COALESCE(MAX(value), 0) FROM settings WHERE name = ?1;",
+                        params![compression_level],
+                        |row| row.get(0),
+                    )?;
+                    let compressed = zstd::encode_all(&record.response_bytes[..], level);
+                    Some(compressed.map_err(|err| {
+                        async_sqlite::rusqlite::Error::ToSqlConversionFailure(err.into())
+                    })?)
+                }
+                None => Some(record.response_bytes),
+            };
+            if let Some(data) = dedupe.then(|| response_bytes.take()).flatten() {
+                // the row's insert trigger counts the reference
+                tx.execute(
+                    "INSERT INTO blobs (hash, data, compressed) VALUES (?1, ?2, ?3);",
+                    params![digest, data, compress],
+                )?;
+            }
+            let replaced = tx.query_row(
+                &exists,
+                params![record.request, record.method, body, vary],
+                |row| row.get::<_, bool>(0),
+            )?;
+            tx.execute(
+                &query,
+                params![
+                    record.request,
+                    record.method,
+                    None::<String>,
+                    record.expires,
+                    record.fetched_at,
+                    digest,
+                    record.status,
+                    body,
+                    record.etag,
+                    response_bytes,
+                    record.content_type,
+                    record.location,
+                    encode_headers(&record.headers),
+                    vary,
+                    hash,
+                    record.final_url,
+                    compress,
+                    record.tag,
+                    record.weak_etag
+                ],
+            )?;
+            // evict in the same transaction so concurrent inserts can't overshoot the limit
+            let evicted = tx.execute(&evict, params![max_entries])?;
+            tx.commit()?;
+            Ok(i64::from(!replaced) - evicted as i64)
+        })
+    })
+    .await?;
+    Ok((true, added))
+}
+
+fn evict_query(table: &str) -> String {
+    // keep the max_entries (?1 names the setting) most recently used rows; without access
+    // tracking a row is only used when it's stored, so this falls back to insertion order
+    // pinned rows count towards the cap but are never evicted, so they can leave more
+    format!("DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} WHERE pinned = 0 ORDER BY last_accessed DESC, rowid DESC LIMIT -1 OFFSET MAX(0, (SELECT COALESCE(MAX(value), 9223372036854775807) FROM settings WHERE name = ?1) - (SELECT COUNT(*) FROM {table} WHERE pinned = 1)));")
+}
+
+fn setting_name(table: &str, name: &str) -> String {
+    // settings are shared by every table, so prefix all but the default table's
+    if table == DEFAULT_TABLE {
+        name.to_string()
+    } else {
+        format!("{table}.{name}")
+    }
+}
+
+pub async fn set_max_entries(connection: &Client, max_entries: Option<usize>) -> Result<(), Error> {
+    // cap the number of stored records, evicting the oldest inserted first; None removes the cap
+    // the cap is kept in the database, so it applies to every connection to it
+    set_max_entries_for(connection, DEFAULT_TABLE, max_entries).await
+}
+
+async fn set_max_entries_for(
+    connection: &Client,
+    table: &str,
+    max_entries: Option<usize>,
+) -> Result<(), Error> {
+    let max_entries = max_entries.map(|max| max as i64);
+    let setting = setting_name(table, "max_entries");
+    let evict = evict_query(table);
+    retry_busy(|| {
+        let setting = setting.clone();
+        let evict = evict.clone();
+        connection.conn(move |conn| {
+            match max_entries {
+                Some(max) => conn.execute("INSERT INTO settings (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET value = excluded.value;", params![setting, max])?,
+                None => conn.execute("DELETE FROM settings WHERE name = ?1;", params![setting])?,
+            };
+            conn.execute(&evict, params![setting])
+        })
+    })
+    .await
+    .map(|_| ())
+}
+
+pub async fn set_track_access(connection: &Client, enabled: bool) -> Result<(), Error> {
+    // record when each cached hit is served, so max_entries evicts the least recently used
+    // this costs a write per hit, so it's off unless turned on, and is kept in the database
+    set_track_access_for(connection, DEFAULT_TABLE, enabled).await
+}
+
+async fn set_track_access_for(
+    connection: &Client,
+    table: &str,
+    enabled: bool,
+) -> Result<(), Error> {
+    let query = "INSERT INTO settings (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET value = excluded.value;";
+    let setting = setting_name(table, "track_access");
+    retry_busy(|| {
+        let setting = setting.clone();
+        connection.conn(move |conn| conn.execute(query, params![setting, enabled]))
+    })
+    .await
+    .map(|_| ())
+}
+
+pub async fn set_deduplication(connection: &Client, enabled: bool) -> Result<(), Error> {
+    // store each distinct body once, shared by every record with the same one, e.g. an error
+    // page many urls return; records already stored are left as they are
+    set_deduplication_for(connection, DEFAULT_TABLE, enabled).await
+}
+
+async fn set_deduplication_for(
+    connection: &Client,
+    table: &str,
+    enabled: bool,
+) -> Result<(), Error> {
+    let query = "INSERT INTO settings (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET value = excluded.value;";
+    let setting = setting_name(table, "deduplication");
+    retry_busy(|| {
+        let setting = setting.clone();
+        connection.conn(move |conn| conn.execute(query, params![setting, enabled]))
+    })
+    .await
+    .map(|_| ())
+}
+
+pub async fn set_compression(connection: &Client, enabled: bool) -> Result<(), Error> {
+    // store new bodies zstd compressed, trading some CPU for a smaller database file
+    // records already stored are left as they are, both kinds read back the same
+    set_compression_for(connection, DEFAULT_TABLE, enabled).await
+}
+
+async fn set_compression_for(connection: &Client, table: &str, enabled: bool) -> Result<(), Error> {
+    let query = "INSERT INTO settings (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET value = excluded.value;";
+    let setting = setting_name(table, "compression");
+    retry_busy(|| {
+        let setting = setting.clone();
+        connection.conn(move |conn| conn.execute(query, params![setting, enabled]))
+    })
+    .await
+    .map(|_| ())
+}
+
+pub async fn set_compression_level(connection: &Client, level: i32) -> Result<(), CacheError> {
+    // the zstd level set_compression compresses new bodies at, higher is smaller but slower;
+    // 0 is zstd's default, and levels zstd doesn't support are refused
+    validate_compression_level(level)?;
+    Ok(set_compression_level_for(connection, DEFAULT_TABLE, level).await?)
+}
+
+fn validate_compression_level(level: i32) -> Result<(), CacheError> {
+    if zstd::compression_level_range().contains(&level) {
+        Ok(())
+    } else {
+        Err(CacheError::InvalidCompressionLevel(level))
+    }
+}
+
+async fn set_compression_level_for(
+    connection: &Client,
+    table: &str,
+    level: i32,
+) -> Result<(), Error> {
+    let query = "INSERT INTO settings (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET value = excluded.value;";
+    let setting = setting_name(table, "compression_level");
+    retry_busy(|| {
+        let setting = setting.clone();
+        connection.conn(move |conn| conn.execute(query, params![setting, level]))
+    })
+    .await
+    .map(|_| ())
+}
+
+fn body_digest(body: &[u8]) -> String {
+    // hex SHA-256 of a response body
+    format!("{:x}", Sha256::digest(body))
+}
+
+fn key_hash(method: &str, url: &str, body: &str) -> String {
+    // hex SHA-256 of a request's cache key, so lookups compare fixed-length values however
+    // long the url; each part is length-prefixed so they can't run into each other
+    let mut hasher = Sha256::new();
+    for part in [method, url, body] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+pub async fn purge_expired(connection: &Client) -> Result<usize, Error> {
+    // delete every expired record that isn't pinned, returning how many were removed
+    purge_expired_from(connection, DEFAULT_TABLE, now_millis()).await
+}
+
+async fn purge_expired_from(connection: &Client, table: &str, now: i64) -> Result<usize, Error> {
+    let query = format!("DELETE FROM {table} WHERE expires <= ?1 AND pinned = 0;");
+    retry_busy(|| {
+        let query = query.clone();
+        connection.conn(move |conn| conn.execute(&query, params![now]))
+    })
+    .await
+}
+
+pub async fn clear_cache(connection: &Client) -> Result<usize, Error> {
+    // delete every record, keeping the database and its settings, returning how many went
+    let cleared = clear_cache_from(connection, DEFAULT_TABLE).await?;
+    Ok(cleared.len())
+}
+
+async fn clear_cache_from(
+    connection: &Client,
+    table: &str,
+) -> Result<Vec<(String, String)>, Error> {
+    let query = format!("DELETE FROM {table} RETURNING request, method;");
+    retry_busy(|| {
+        let query = query.clone();
+        connection.conn(move |conn| deleted_requests(conn, &query, []))
+    })
+    .await
+}
+
+pub async fn vacuum(connection: &Client) -> Result<(), Error> {
+    // give the space of deleted records back to the filesystem, e.g. after clear_cache
+    connection.conn(|conn| conn.execute_batch("VACUUM;")).await
+}
+
+#[cfg(feature = "json")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ExportedRecord {
+    // what the record is keyed on besides its url and method
+    body: String,
+    vary: String,
+    record: Record,
+}
+
+#[cfg(feature = "json")]
+pub async fn export_json(connection: &Client) -> Result<String, CacheError> {
+    // every stored record as a JSON array, for import_json to load into another cache
+    export_json_from(connection, DEFAULT_TABLE).await
+}
+
+#[cfg(feature = "json")]
+async fn export_json_from(connection: &Client, table: &str) -> Result<String, CacheError> {
+    let query = format!("SELECT {RECORD_COLUMNS}, vary FROM {table} ORDER BY rowid;");
+    let rows = connection
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map([], |row| {
+                Ok(ExportedRecord {
+                    body: row.get("body")?,
+                    vary: row.get("vary")?,
+                    record: record_from_row(row)?,
+                })
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+    serde_json::to_string(&rows).map_err(CacheError::Serialize)
+}
+
+#[cfg(feature = "json")]
+pub async fn import_json(connection: &Client, json: &str) -> Result<usize, CacheError> {
+    // store the records of an export_json dump, replacing any with the same key, returning
+    // how many were stored; records that have expired since the export are skipped
+    import_json_into(connection, DEFAULT_TABLE, json, now_millis()).await
+}
+
+#[cfg(feature = "json")]
+async fn import_json_into(
+    connection: &Client,
+    table: &str,
+    json: &str,
+    now: i64,
+) -> Result<usize, CacheError> {
+    let rows: Vec<ExportedRecord> =
+        serde_json::from_str(json).map_err(|err| CacheError::Deserialize(err, json.to_string()))?;
+    let mut imported = 0;
+    for row in rows.into_iter().filter(|row| !row.record.is_expired(now)) {
+        // the Vary values stand in for the request headers that selected them
+        let request_headers = decode_headers(Some(row.vary));
+        insert_record(connection, table, row.record, &row.body, &request_headers).await?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+pub async fn invalidate_before(connection: &Client, timestamp: i64) -> Result<(), Error> {
+    // treat every record fetched before timestamp (in milliseconds) as stale
+    invalidate_before_for(connection, DEFAULT_TABLE, timestamp).await
+}
+
+async fn invalidate_before_for(
+    connection: &Client,
+    table: &str,
+    timestamp: i64,
+) -> Result<(), Error> {
+    let query = "INSERT INTO settings (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET value = excluded.value;";
+    let setting = setting_name(table, "invalidation_epoch");
+    retry_busy(|| {
+        let setting = setting.clone();
+        connection.conn(move |conn| conn.execute(query, params![setting, timestamp]))
+    })
+    .await
+    .map(|_| ())
+}
+
+pub async fn touch(
+    connection: &Client,
+    url: String,
+    method: String,
+    new_timeout: i64,
+) -> Result<bool, Error> {
+    // make every stored variant of a request expire new_timeout seconds from now without
+    // refetching it, e.g. when told out of band it hasn't changed; false if none is stored
+    let expires = now_millis().saturating_add(new_timeout.saturating_mul(1000));
+    touch_from(connection, DEFAULT_TABLE, url, method, expires).await
+}
+
+async fn touch_from(
+    connection: &Client,
+    table: &str,
+    url: String,
+    method: String,
+    expires: i64,
+) -> Result<bool, Error> {
+    let query = format!("UPDATE {table} SET expires = ?3 WHERE request = ?1 AND method = ?2;");
+    let method = normalize_method(&method);
+    let updated = retry_busy(|| {
+        let (query, url, method) = (query.clone(), url.clone(), method.clone());
+        connection.conn(move |conn| conn.execute(&query, params![url, method, expires]))
+    })
+    .await?;
+    Ok(updated > 0)
+}
+
+pub async fn pin(connection: &Client, url: String, method: String) -> Result<bool, Error> {
+    // keep every stored variant of a request from being purged or evicted, e.g. one that's
+    // expensive to fetch again; it's still refreshed, and removed by invalidation, as any
+    // other record; false if none is stored
+    set_pinned_from(connection, DEFAULT_TABLE, url, method, true).await
+}
+
+pub async fn unpin(connection: &Client, url: String, method: String) -> Result<bool, Error> {
+    // let a pinned request be purged and evicted again; false if none is stored
+    set_pinned_from(connection, DEFAULT_TABLE, url, method, false).await
+}
+
+async fn set_pinned_from(
+    connection: &Client,
+    table: &str,
+    url: String,
+    method: String,
+    pinned: bool,
+) -> Result<bool, Error> {
+    let query = format!("UPDATE {table} SET pinned = ?3 WHERE request = ?1 AND method = ?2;");
+    let method = normalize_method(&method);
+    let updated = retry_busy(|| {
+        let (query, url, method) = (query.clone(), url.clone(), method.clone());
+        connection.conn(move |conn| conn.execute(&query, params![url, method, pinned]))
+    })
+    .await?;
+    Ok(updated > 0)
+}
+
+pub async fn invalidate(connection: &Client, url: String, method: String) -> Result<usize, Error> {
+    // delete every stored variant of one request, returning how many records went
+    invalidate_from(connection, DEFAULT_TABLE, url, method).await
+}
+
+async fn invalidate_from(
+    connection: &Client,
+    table: &str,
+    url: String,
+    method: String,
+) -> Result<usize, Error> {
+    let query = format!("DELETE FROM {table} WHERE request = ?1 AND method = ?2;");
+    let method = normalize_method(&method);
+    retry_busy(|| {
+        let (query, url, method) = (query.clone(), url.clone(), method.clone());
+        connection.conn(move |conn| conn.execute(&query, params![url, method]))
+    })
+    .await
+}
+
+pub async fn invalidate_url(connection: &Client, url: String) -> Result<usize, Error> {
+    // as invalidate, for every method
+    let invalidated = invalidate_url_from(connection, DEFAULT_TABLE, url).await?;
+    Ok(invalidated.len())
+}
+
+async fn invalidate_url_from(
+    connection: &Client,
+    table: &str,
+    url: String,
+) -> Result<Vec<(String, String)>, Error> {
+    let query = format!("DELETE FROM {table} WHERE request = ?1 RETURNING request, method;");
+    retry_busy(|| {
+        let (query, url) = (query.clone(), url.clone());
+        connection.conn(move |conn| deleted_requests(conn, &query, params![url]))
+    })
+    .await
+}
+
+// one part of a response fetched by range: its first byte, the full length, and the etag
+// that ties it to one version of the resource
+pub(crate) struct RangePart {
+    pub(crate) start: u64,
+    pub(crate) total: u64,
+    pub(crate) etag: Option<String>,
+    pub(crate) data: Vec<u8>,
+}
+
+async fn store_range_from(
+    connection: &Client,
+    table: &str,
+    url: String,
+    part: RangePart,
+) -> Result<Vec<RangePart>, Error> {
+    // keep part, dropping any parts of another version of the resource, and return every
+    // part stored for url in order
+    let table = table.to_string();
+    connection
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM ranges WHERE cache_table = ?1 AND request = ?2 AND (total != ?3 OR etag IS NOT ?4);",
+                params![table, url, part.total as i64, part.etag],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO ranges (cache_table, request, start, total, etag, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+                params![table, url, part.start as i64, part.total as i64, part.etag, part.data],
+            )?;
+            let parts = {
+                let mut statement = tx.prepare(
+                    "SELECT start, total, etag, data FROM ranges WHERE cache_table = ?1 AND request = ?2 ORDER BY start;",
+                )?;
+                let rows = statement.query_map(params![table, url], |row| {
+                    Ok(RangePart {
+                        start: row.get::<_, i64>(0)? as u64,
+                        total: row.get::<_, i64>(1)? as u64,
+                        etag: row.get(2)?,
+                        data: row.get(3)?,
+                    })
+                })?;
+                rows.collect::<Result<Vec<_>, _>>()?
+            };
+            tx.commit()?;
+            Ok(parts)
+        })
+        .await
+}
+
+async fn delete_ranges_from(connection: &Client, table: &str, url: String) -> Result<(), Error> {
+    let table = table.to_string();
+    connection
+        .conn(move |conn| {
+            let query = "DELETE FROM ranges WHERE cache_table = ?1 AND request = ?2;";
+            conn.execute(query, params![table, url]).map(|_| ())
+        })
+        .await
+}
+
+pub(crate) fn assemble_ranges(parts: &[RangePart]) -> Option<Vec<u8>> {
+    // the whole body once parts, in order, cover every byte of it, overlaps allowed
+    let total = parts.first()?.total;
+    let mut body: Vec<u8> = Vec::new();
+    for part in parts {
+        let covered = body.len() as u64;
+        if part.start > covered {
+            return None;
+        }
+        let skip = (covered - part.start) as usize;
+        body.extend(part.data.iter().skip(skip));
+    }
+    (body.len() as u64 == total).then_some(body)
+}
+
+async fn stored_request_from(
+    connection: &Client,
+    table: &str,
+    url: String,
+    method: String,
+) -> Result<Option<(String, String)>, Error> {
+    // the keyed body and vary key of the newest record for url and method, however old
+    let query = format!(
+        "SELECT body, vary FROM {table} WHERE request = ?1 AND method = ?2 ORDER BY fetched_at DESC LIMIT 1;"
+    );
+    connection
+        .conn(move |conn| {
+            conn.query_row(&query, params![url, method], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()
+        })
+        .await
+}
+
+pub async fn invalidate_tag(connection: &Client, tag: String) -> Result<usize, Error> {
+    // delete every record stored with tag, returning how many went
+    let invalidated = invalidate_tag_from(connection, DEFAULT_TABLE, tag).await?;
+    Ok(invalidated.len())
+}
+
+async fn invalidate_tag_from(
+    connection: &Client,
+    table: &str,
+    tag: String,
+) -> Result<Vec<(String, String)>, Error> {
+    let query = format!("DELETE FROM {table} WHERE tag = ?1 RETURNING request, method;");
+    retry_busy(|| {
+        let (query, tag) = (query.clone(), tag.clone());
+        connection.conn(move |conn| deleted_requests(conn, &query, params![tag]))
+    })
+    .await
+}
+
+pub async fn invalidate_prefix(connection: &Client, prefix: String) -> Result<usize, Error> {
+    // delete every record whose url starts with prefix, e.g. "https://api.test/users/"
+    let invalidated = invalidate_prefix_from(connection, DEFAULT_TABLE, prefix).await?;
+    Ok(invalidated.len())
+}
+
+async fn invalidate_prefix_from(
+    connection: &Client,
+    table: &str,
+    prefix: String,
+) -> Result<Vec<(String, String)>, Error> {
+    // compared with substr rather than LIKE, so % and _ in the prefix are matched as is
+    let query = format!(
+        "DELETE FROM {table} WHERE substr(request, 1, length(?1)) = ?1 RETURNING request, method;"
+    );
+    retry_busy(|| {
+        let (query, prefix) = (query.clone(), prefix.clone());
+        connection.conn(move |conn| deleted_requests(conn, &query, params![prefix]))
+    })
+    .await
+}
+
+pub async fn purge_where(connection: &Client, criteria: PurgeCriteria) -> Result<usize, Error> {
+    // delete every record matching criteria, returning how many were removed
+    let purged = purge_where_from(connection, DEFAULT_TABLE, criteria, now_millis()).await?;
+    Ok(purged.len())
+}
+
+async fn purge_where_from(
+    connection: &Client,
+    table: &str,
+    criteria: PurgeCriteria,
+    now: i64,
+) -> Result<Vec<(String, String)>, Error> {
+    // every value is bound as a parameter, only the fixed conditions are written into the SQL
+    let mut conditions = vec!["1 = 1".to_string()];
+    let mut values: Vec<Value> = Vec::new();
+    if let Some(bytes) = criteria.larger_than {
+        values.push(Value::Integer(bytes.try_into().unwrap_or(i64::MAX)));
+        let stored = "COALESCE(response_bytes, (SELECT data FROM blobs WHERE hash = digest))";
+        conditions.push(format!("length({stored}) > ?{}", values.len()));
+    }
+    if let Some(age) = criteria.older_than {
+        let age: i64 = age.as_millis().try_into().unwrap_or(i64::MAX);
+        values.push(Value::Integer(now.saturating_sub(age)));
+        conditions.push(format!("fetched_at < ?{}", values.len()));
+    }
+    if let Some(tag) = criteria.tag {
+        values.push(Value::Text(tag));
+        conditions.push(format!("tag = ?{}", values.len()));
+    }
+    let select = format!(
+        "SELECT rowid, request, method FROM {table} WHERE {};",
+        conditions.join(" AND ")
+    );
+    let delete = format!("DELETE FROM {table} WHERE rowid = ?1;");
+    let host = criteria.host;
+    retry_busy(|| {
+        let (select, delete) = (select.clone(), delete.clone());
+        let (values, host) = (values.clone(), host.clone());
+        connection.conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let mut deleted = Vec::new();
+            {
+                let mut stmt = tx.prepare(&select)?;
+                let rows = stmt.query_map(params_from_iter(values), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?))
+                })?;
+                let rows = rows.collect::<Result<Vec<_>, _>>()?;
+                // SQL has no url parser, so the host is compared here, as breakdown_by_host does
+                let mut delete = tx.prepare(&delete)?;
+                for (rowid, request, method) in rows {
+                    let on_host = host.as_ref().map_or(true, |host| {
+                        reqwest::Url::parse(&request)
+                            .is_ok_and(|url| url.host_str() == Some(host.as_str()))
+                    });
+                    if on_host && delete.execute(params![rowid])? > 0 {
+                        deleted.push((request, method));
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(deleted)
+        })
+    })
+    .await
+}
+
+pub async fn verify_all(connection: &Client, delete_corrupt: bool) -> Result<VerifyReport, Error> {
+    // check every stored body against its digest, records without one are skipped
+    verify_all_from(connection, DEFAULT_TABLE, delete_corrupt).await
+}
+
+async fn verify_all_from(
+    connection: &Client,
+    table: &str,
+    delete_corrupt: bool,
+) -> Result<VerifyReport, Error> {
+    let query = format!("SELECT rowid, request, method, COALESCE(response_bytes, (SELECT data FROM blobs WHERE hash = digest)), digest, compressed FROM {table} WHERE digest IS NOT NULL;");
+    let rows = connection
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<Vec<u8>>>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, bool>(5)?,
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+    let mut report = VerifyReport {
+        checked: rows.len(),
+        ..Default::default()
+    };
+    let mut corrupt_rows = Vec::new();
+    for (rowid, request, method, response, digest, compressed) in rows {
+        // the digest is of the body as received, so a compressed one that won't decode is corrupt,
+        // as is a row whose deduplicated body has gone from blobs
+        let response = match response {
+            Some(response) if compressed => zstd::decode_all(&response[..]).ok(),
+            response => response,
+        };
+        if response.map_or(true, |response| body_digest(&response) != digest) {
+            corrupt_rows.push(rowid);
+            report.corrupt.push((request, method));
+        }
+    }
+    if delete_corrupt {
+        report.deleted = delete_rows(connection, table, corrupt_rows).await?;
+    }
+    Ok(report)
+}
+
+pub async fn put(connection: &Client, record: Record) -> Result<(), Error> {
+    // store a record as if it had been fetched, replacing any existing one
+    insert_record(connection, DEFAULT_TABLE, record, "", &[])
+        .await
+        .map(|_| ())
+}
+
+const BUSY_RETRIES: u32 = 5;
+// how long sqlite itself waits on a lock before reporting the database busy
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn retry_busy<T, F, Fut>(mut operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    // retry an operation that hit a locked database, backing off between attempts
+    let mut delay = Duration::from_millis(10);
+    for _ in 1..BUSY_RETRIES {
+        match operation().await {
+            Err(err) if is_busy(&err) => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    operation().await
+}
+
+fn is_busy(err: &Error) -> bool {
+    // only lock contention is retried, any other error is returned straight away
+    match err {
+        Error::Rusqlite(async_sqlite::rusqlite::Error::SqliteFailure(err, _)) => {
+            matches!(
+                err.code,
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked
+            )
+        }
+        _ => false,
+    }
+}
+
+pub async fn invalidate_where_body(
+    connection: &Client,
+    predicate: impl Fn(&str) -> bool,
+) -> Result<usize, Error> {
+    // delete every record whose stored response matches predicate
+    invalidate_where_body_from(connection, DEFAULT_TABLE, predicate).await
+}
+
+async fn invalidate_where_body_from(
+    connection: &Client,
+    table: &str,
+    predicate: impl Fn(&str) -> bool,
+) -> Result<usize, Error> {
+    let query = format!("SELECT rowid, {RECORD_COLUMNS} FROM {table};");
+    let rows = connection
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, record_from_row(row)?.response))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+    let rowids = rows
+        .into_iter()
+        .filter(|(_, response)| predicate(response))
+        .map(|(rowid, _)| rowid)
+        .collect();
+    // SQL can't run the predicate, so delete the matching rows by id
+    delete_rows(connection, table, rowids).await
+}
+
+async fn delete_stored(
+    connection: &Client,
+    table: &str,
+    stored: StoredBody,
+) -> Result<usize, Error> {
+    // delete the row a body was read from, unless it's been replaced since
+    let query = format!("DELETE FROM {table} WHERE rowid = ?1 AND digest IS ?2;");
+    retry_busy(|| {
+        let query = query.clone();
+        let digest = stored.digest.clone();
+        connection.conn(move |conn| conn.execute(&query, params![stored.rowid, digest]))
+    })
+    .await
+}
+
+fn deleted_requests(
+    conn: &Connection,
+    query: &str,
+    params: impl async_sqlite::rusqlite::Params,
+) -> Result<Vec<(String, String)>, async_sqlite::rusqlite::Error> {
+    // runs a DELETE ... RETURNING request, method, giving the (url, method) of every row it removed
+    let mut stmt = conn.prepare(query)?;
+    let rows = stmt.query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+async fn delete_rows(connection: &Client, table: &str, rowids: Vec<i64>) -> Result<usize, Error> {
+    // delete the given rows in a single transaction
+    let query = format!("DELETE FROM {table} WHERE rowid = ?1;");
+    connection
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let mut deleted = 0;
+            {
+                let mut stmt = tx.prepare(&query)?;
+                for rowid in &rowids {
+                    deleted += stmt.execute(params![rowid])?;
+                }
+            }
+            tx.commit()?;
+            Ok(deleted)
+        })
+        .await
+}
+
+#[cfg(feature = "sql-trace")]
+pub async fn set_sql_trace(
+    connection: &Client,
+    trace: Option<fn(&str, std::time::Duration)>,
+) -> Result<(), Error> {
+    // report every statement run on this connection and how long it took, None turns it off
+    connection
+        .conn_mut(move |conn| {
+            conn.profile(trace);
+            Ok(())
+        })
+        .await
+}
+
+pub async fn breakdown_by_method(connection: &Client) -> Result<HashMap<String, i64>, Error> {
+    // count the stored records for each method
+    breakdown_by_method_from(connection, DEFAULT_TABLE).await
+}
+
+async fn breakdown_by_method_from(
+    connection: &Client,
+    table: &str,
+) -> Result<HashMap<String, i64>, Error> {
+    let query = format!("SELECT method, COUNT(*) FROM {table} GROUP BY method;");
+    connection
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        })
+        .await
+}
+
+pub async fn breakdown_by_host(connection: &Client) -> Result<HashMap<String, i64>, Error> {
+    // count the stored records for each URL host, URLs without one count under ""
+    breakdown_by_host_from(connection, DEFAULT_TABLE).await
+}
+
+async fn breakdown_by_host_from(
+    connection: &Client,
+    table: &str,
+) -> Result<HashMap<String, i64>, Error> {
+    let query = format!("SELECT request FROM {table};");
+    let urls = connection
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            rows.collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+    let mut counts = HashMap::new();
+    for url in urls {
+        let host = reqwest::Url::parse(&url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        *counts.entry(host).or_insert(0) += 1;
+    }
+    Ok(counts)
+}
+
+pub async fn cache_len(connection: &Client) -> Result<usize, Error> {
+    // count the stored records, expired ones included until they're purged
+    cache_len_from(connection, DEFAULT_TABLE).await
+}
+
+async fn cache_len_from(connection: &Client, table: &str) -> Result<usize, Error> {
+    let query = format!("SELECT COUNT(*) FROM {table};");
+    let count: i64 = connection
+        .conn(move |conn| conn.query_row(&query, [], |row| row.get(0)))
+        .await?;
+    Ok(count as usize)
+}
+
+pub async fn cache_size_bytes(connection: &Client) -> Result<u64, Error> {
+    // the size of the whole database, which only shrinks on a vacuum, not a purge
+    let query = "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size();";
+    let size: i64 = connection
+        .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+        .await?;
+    Ok(size as u64)
+}
+
+pub async fn storage_info(connection: &Client) -> Result<StorageInfo, Error> {
+    // the database's size with its -wal and -shm files'
+    let query = "SELECT file FROM pragma_database_list WHERE name = 'main';";
+    let path: String = connection
+        .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+        .await?;
+    // a database in memory has no file, so no path
+    let sidecar = |suffix| {
+        let metadata = std::fs::metadata(format!("{path}{suffix}"));
+        metadata
+            .ok()
+            .filter(|_| !path.is_empty())
+            .map(|file| file.len())
+    };
+    Ok(StorageInfo {
+        database_bytes: cache_size_bytes(connection).await?,
+        wal_bytes: sidecar("-wal"),
+        shm_bytes: sidecar("-shm"),
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn make_request<S: CacheStore>(
+    connection: &S,
+    client: &reqwest::Client,
+    url: &str,
+    fallbacks: &[String],
+    method: &Method,
+    body: Option<String>,
+    ttl_millis: i64,
+    user_agent: Option<String>,
+    headers: Option<Vec<(String, String)>>,
+    request_timeout: Option<Duration>,
+    freshness: Freshness,
+    skip_error_status: bool,
+    stale: Option<Record>,
+    retry: Option<&RetryPolicy>,
+    negative_ttl_millis: Option<i64>,
+    max_response_bytes: Option<usize>,
+    rate_limiter: Option<&RateLimiter>,
+    should_cache: Option<&ShouldCacheFn>,
+    tag: Option<&str>,
+    expiry_jitter: Option<f64>,
+    clock: &dyn Clock,
+) -> Result<Record, CacheError> {
+    // make an HTTP request and cache the resulting Record
+    let key_body = body.clone().unwrap_or_default();
+    let sent_headers = request_headers(&user_agent, &headers);
+    let if_none_match = stale.as_ref().and_then(|record| record.etag.clone());
+    let if_modified_since = stale
+        .as_ref()
+        .and_then(|record| record.header(LAST_MODIFIED.as_str()))
+        .map(str::to_string);
+    // transient failures are tried again as the retry policy allows, one attempt without one
+    // while fallbacks remain, an error or a status other than success or 304 moves on to the
+    // next url, which gets its own attempts; the last url's response is kept whatever it is
+    let start = std::time::Instant::now();
+    let mut attempt;
+    let targets: Vec<&str> = std::iter::once(url)
+        .chain(fallbacks.iter().map(String::as_str))
+        .collect();
+    let mut record = 'targets: {
+        for (index, target) in targets.iter().enumerate() {
+            attempt = 1;
+            let result = loop {
+                // only requests that reach the server are throttled, cache hits never get here
+                if let Some(rate_limiter) = rate_limiter {
+                    rate_limiter.acquire(target).await;
+                }
+                let result = fetch(
+                    client,
+                    target,
+                    method,
+                    body.clone(),
+                    ttl_millis,
+                    user_agent.clone(),
+                    headers.clone(),
+                    request_timeout,
+                    freshness,
+                    if_none_match.clone(),
+                    if_modified_since.clone(),
+                    max_response_bytes,
+                    clock,
+                )
+                .await;
+                match retry {
+                    Some(retry) if attempt < retry.max_attempts && retry.should_retry(&result) => {
+                        tokio::time::sleep(retry.delay(attempt)).await;
+                        attempt += 1;
+                    }
+                    _ => break result,
+                }
+            };
+            let answered = matches!(&result, Ok(record) if record.status == 304 || (200..300).contains(&record.status));
+            if answered || index + 1 == targets.len() {
+                break 'targets result?;
+            }
+            debug!(url = %target, "request failed, trying the next fallback");
+        }
+        unreachable!("the primary url is always tried")
+    };
+    // a fallback's response is stored as the primary's, so the record names only the primary
+    if record.request != url {
+        record.request = url.to_string();
+        record.final_url = url.to_string();
+    }
+    record.fetch_duration_ms = Some(start.elapsed().as_millis() as u64);
+    debug!(
+        status = record.status,
+        elapsed_ms = record.fetch_duration_ms,
+        attempts = attempt,
+        "response received"
+    );
+    // a 304 means the stored body is still current, so only its expiry moves on
+    if record.status == 304 {
+        match stale {
+            Some(stale) if confirms(&record, &stale) => {
+                // headers sent with the 304 replace the stored ones of the same name
+                let mut headers: Vec<_> = stale
+                    .headers
+                    .into_iter()
+                    .filter(|(name, _)| record.header(name).is_none())
+                    .collect();
+                headers.extend(record.headers);
+                let weak_etag = match record.etag {
+                    Some(_) => record.weak_etag,
+                    None => stale.weak_etag,
+                };
+                let mut record = Record {
+                    response: stale.response,
+                    response_bytes: stale.response_bytes,
+                    content_type: stale.content_type,
+                    status: stale.status,
+                    cached: true,
+                    etag: record.etag.or(stale.etag),
+                    weak_etag,
+                    headers,
+                    revalidated: true,
+                    tag: tag.map(str::to_string).or(stale.tag),
+                    ..record
+                };
+                jitter_expiry(&mut record, expiry_jitter);
+                return store(connection, record, &key_body, &sent_headers).await;
+            }
+            Some(_) => {
+                // the 304 is for a representation other than the stored one, which it can't
+                // freshen, so the response is fetched again in full
+                debug!("304 doesn't match the stored etag, refetching");
+                if let Some(rate_limiter) = rate_limiter {
+                    rate_limiter.acquire(url).await;
+                }
+                record = fetch(
+                    client,
+                    url,
+                    method,
+                    body.clone(),
+                    ttl_millis,
+                    user_agent.clone(),
+                    headers.clone(),
+                    request_timeout,
+                    freshness,
+                    None,
+                    None,
+                    max_response_bytes,
+                    clock,
+                )
+                .await?;
+            }
+            None => {}
+        }
+    }
+    expire_errors(&mut record, skip_error_status, negative_ttl_millis);
+    skip_unwanted(&mut record, should_cache);
+    jitter_expiry(&mut record, expiry_jitter);
+    record.tag = tag.map(str::to_string);
+    store(connection, record, &key_body, &sent_headers).await
+}
+
+fn confirms(not_modified: &Record, stored: &Record) -> bool {
+    // whether a 304 applies to the stored record, as RFC 7232 compares etags: a strong etag
+    // only matches an identical strong one, a weak etag matches either kind with the same
+    // opaque tag; a 304 without one confirms whatever the request's validators selected
+    let (Some(received), Some(stored_etag)) = (&not_modified.etag, &stored.etag) else {
+        return true;
+    };
+    let opaque = |etag: &'_ str| etag.strip_prefix("W/").unwrap_or(etag).to_string();
+    if not_modified.weak_etag {
+        opaque(received) == opaque(stored_etag)
+    } else {
+        !stored.weak_etag && received == stored_etag
+    }
+}
+
+pub(crate) fn jitter_expiry(record: &mut Record, jitter: Option<f64>) {
+    // move expires by up to jitter of the record's lifetime either way, so records stored
+    // together don't all expire together; a record that won't be stored is left alone
+    let lifetime = record.expires.saturating_sub(record.fetched_at);
+    let Some(jitter) = jitter.filter(|&jitter| jitter > 0.0 && lifetime > 0) else {
+        return;
+    };
+    // each RandomState is seeded differently, which is random enough to spread expiries
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    let offset = (lifetime as f64 * jitter * (2.0 * random - 1.0)) as i64;
+    record.expires = record.fetched_at + lifetime.saturating_add(offset).max(1);
+}
+
+// decides from a fetched record whether it's stored
+pub(crate) type ShouldCacheFn = Arc<dyn Fn(&Record) -> bool + Send + Sync>;
+
+pub(crate) fn skip_unwanted(record: &mut Record, should_cache: Option<&ShouldCacheFn>) {
+    // a record should_cache turns down is expired, so store leaves it out like any other
+    if should_cache.is_some_and(|should_cache| !should_cache(record)) {
+        record.expires = record.fetched_at;
+    }
+}
+
+fn expire_errors(record: &mut Record, skip_error_status: bool, negative_ttl_millis: Option<i64>) {
+    // error responses are often transient, so optionally don't cache them at all
+    if skip_error_status && record.status >= 400 {
+        record.expires = record.fetched_at;
+    }
+    // server errors are transient, so never cached; with negative caching, client errors are
+    // kept for negative_ttl seconds; responses that wouldn't be stored anyway are left alone
+    if record.status >= 500 {
+        record.expires = record.fetched_at;
+    } else if let Some(ttl) = negative_ttl_millis.filter(|_| record.expires > record.fetched_at) {
+        if record.status >= 400 {
+            record.expires = record.fetched_at + ttl;
+        }
+    }
+}
+
+fn http_client() -> &'static reqwest::Client {
+    // one client for every request through the free functions, so repeated misses share
+    // its connection pool
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+    method: &Method,
+    body: Option<String>,
+    ttl_millis: i64,
+    user_agent: Option<String>,
+    extra_headers: Option<Vec<(String, String)>>,
+    request_timeout: Option<Duration>,
+    freshness: Freshness,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    max_response_bytes: Option<usize>,
+    clock: &dyn Clock,
+) -> Result<Record, CacheError> {
+    // make an HTTP request and create a Record
+    let started = start_fetch(
+        client,
+        url,
+        method,
+        body,
+        ttl_millis,
+        user_agent,
+        extra_headers,
+        request_timeout,
+        freshness,
+        if_none_match,
+        if_modified_since,
+        max_response_bytes,
+        clock,
+    );
+    let (mut record, mut response) = started.await?;
+    // read the body a chunk at a time, so an oversized one is never buffered whole
+    // a HEAD response has no body whatever its Content-Length says, so only the status and
+    // headers are stored
+    if *method != Method::HEAD {
+        while let Some(chunk) = response.chunk().await? {
+            record.response_bytes.extend_from_slice(&chunk);
+            if let Some(max) = max_response_bytes.filter(|&max| record.response_bytes.len() > max) {
+                return Err(CacheError::TooLarge(max));
+            }
+        }
+    }
+    record.response = String::from_utf8_lossy(&record.response_bytes).into_owned();
+    Ok(record)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn start_fetch(
+    client: &reqwest::Client,
+    url: &str,
+    method: &Method,
+    body: Option<String>,
+    ttl_millis: i64,
+    user_agent: Option<String>,
+    extra_headers: Option<Vec<(String, String)>>,
+    request_timeout: Option<Duration>,
+    freshness: Freshness,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    max_response_bytes: Option<usize>,
+    clock: &dyn Clock,
+) -> Result<(Record, reqwest::Response), CacheError> {
+    // send an HTTP request and create a Record from the response's status and headers,
+    // leaving the body for the caller to read
+    let mut headers = HeaderMap::new();
+    if let Some(user_agent) = user_agent {
+        headers.insert(USER_AGENT, user_agent.parse()?);
+    }
+    // extra headers only key the record when a response's Vary names them, or, with
+    // auth_in_key, as a digest of Authorization added to the keyed body
+    for (name, value) in extra_headers.unwrap_or_default() {
+        headers.append(HeaderName::from_bytes(name.as_bytes())?, value.parse()?);
+    }
+    // a server checks whichever validators it supports, ETag first if it has both
+    if let Some(etag) = if_none_match {
+        headers.insert(IF_NONE_MATCH, etag.parse()?);
+    }
+    if let Some(modified) = if_modified_since {
+        headers.insert(IF_MODIFIED_SINCE, modified.parse()?);
+    }
+
+    let mut builder = client.request(method.clone(), url).headers(headers);
+    if let Some(body) = body.clone() {
+        builder = builder.body(body);
+    }
+    // timeout is how long the record stays cached, request_timeout how long to wait for the server
+    if let Some(request_timeout) = request_timeout {
+        builder = builder.timeout(request_timeout);
+    }
+    let response = builder.send().await?;
+    let status = response.status().as_u16();
+    // reqwest normalises urls, so only report a different one if a redirect was followed
+    let final_url = match reqwest::Url::parse(url) {
+        Ok(requested) if &requested == response.url() => url.to_string(),
+        _ => response.url().to_string(),
+    };
+    let fetched_at = clock.now_millis();
+    // expires ttl_millis after now, unless freshness lets the server say otherwise;
+    // a ttl of 0 still means don't store, whatever the headers say
+    let lifetime = match freshness {
+        _ if ttl_millis <= 0 => 0,
+        Freshness::Ttl => ttl_millis,
+        Freshness::PreferHeaders => {
+            header_lifetime(response.headers(), fetched_at).unwrap_or(ttl_millis)
+        }
+        Freshness::HeadersOnly => header_lifetime(response.headers(), fetched_at)
+            .or_else(|| heuristic_lifetime(status, response.headers(), fetched_at))
+            .unwrap_or(0),
+    };
+    let expiry_timestamp = fetched_at + lifetime;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(ETAG);
+    let weak_etag = etag.as_ref().is_some_and(|etag| etag.starts_with("W/"));
+    let content_type = header(CONTENT_TYPE);
+    let location = header(LOCATION);
+    let headers = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.to_string(), value)
+        })
+        .collect();
+    // give up before reading any of the body if Content-Length is already over the limit
+    let over_limit = |len: u64| max_response_bytes.filter(|&max| len > max as u64);
+    if *method != Method::HEAD {
+        if let Some(max) = response.content_length().and_then(over_limit) {
+            return Err(CacheError::TooLarge(max));
+        }
+    }
+    let record = Record {
+        request: url.to_string(),
+        final_url,
+        method: method.to_string(),
+        body: body.filter(|body| !body.is_empty()),
+        response: String::new(),
+        response_bytes: Vec::new(),
+        content_type,
+        status,
+        expires: expiry_timestamp,
+        cached: false,
+        fetched_at,
+        changed: None,
+        etag,
+        weak_etag,
+        location,
+        headers,
+        stale: false,
+        revalidated: false,
+        tag: None,
+        fetch_duration_ms: None,
+        storage_failed: false,
+    };
+    Ok((record, response))
+}
+
+fn header_lifetime(headers: &HeaderMap, now: i64) -> Option<i64> {
+    // milliseconds the response may be cached for according to Cache-Control or Expires
+    let cache_control = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase());
+    let mut max_age = None;
+    for directive in cache_control {
+        if directive == "no-store" || directive == "no-cache" {
+            return Some(0);
+        }
+        if let Some(seconds) = directive.strip_prefix("max-age=") {
+            max_age = seconds.trim_matches('"').parse::<i64>().ok();
+        }
+    }
+    // the time the response already spent in caches upstream comes off its lifetime
+    let date = header_date(headers, DATE);
+    let age = headers
+        .get(AGE)
+        .and_then(|value| value.to_str().ok()?.trim().parse::<i64>().ok())
+        .map_or(0, |seconds| seconds.saturating_mul(1000));
+    let age = age.max(date.map_or(0, |date| now - date)).max(0);
+    // max-age takes precedence over Expires
+    if let Some(seconds) = max_age {
+        return Some((seconds.max(0).saturating_mul(1000) - age).max(0));
+    }
+    headers.get(EXPIRES)?;
+    // an unparseable Expires means already expired; it's relative to the server's Date
+    // when there is one, so a skewed local clock doesn't matter
+    let lifetime = match (header_date(headers, EXPIRES), date) {
+        (Some(expires), Some(date)) => expires - date - age,
+        (Some(expires), None) => expires - now,
+        (None, _) => 0,
+    };
+    Some(lifetime.max(0))
+}
+
+fn heuristic_lifetime(status: u16, headers: &HeaderMap, now: i64) -> Option<i64> {
+    // a tenth of the time since Last-Modified, for statuses cacheable by default, and at
+    // most a day, past which RFC 7234 would have a warning attached
+    const HEURISTIC_STATUSES: [u16; 11] = [200, 203, 204, 206, 300, 301, 404, 405, 410, 414, 501];
+    const MAX_HEURISTIC: i64 = 24 * 60 * 60 * 1000;
+    if !HEURISTIC_STATUSES.contains(&status) {
+        return None;
+    }
+    let modified = header_date(headers, LAST_MODIFIED)?;
+    let date = header_date(headers, DATE).unwrap_or(now);
+    Some(((date - modified) / 10).clamp(0, MAX_HEURISTIC))
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<i64> {
+    // an HTTP date header in milliseconds since the epoch
+    let value = headers.get(name)?.to_str().ok()?;
+    let time = httpdate::parse_http_date(value).ok()?;
+    let since = time.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some(since.as_millis() as i64)
+}
+
+async fn store<S: CacheStore>(
+    connection: &S,
+    mut record: Record,
+    body: &str,
+    request_headers: &[(String, String)],
+) -> Result<Record, CacheError> {
+    // add to the cache, unless the record is already expired, e.g. a timeout of 0
+    if record.expires > record.fetched_at {
+        let inserted = connection.insert_record(record.clone(), body, request_headers);
+        match inserted.await {
+            Ok(changed) => record.changed = Some(changed),
+            // the store has logged the failure and asked for the response to be served
+            Err(CacheError::NotStored(_)) => record.storage_failed = true,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(record)
+}
+
+pub async fn request_with_fallbacks<S: CacheStore>(
+    connection: &S,
+    primary: String,
+    fallbacks: &[String],
+    method: String,
+    timeout: i64,
+    user_agent: Option<String>,
+) -> Result<Record, CacheError> {
+    // try primary then each fallback in turn, until one answers with a success, caching the
+    // response under primary; a cached primary is served without trying any of them
+    let options = RequestOptions {
+        user_agent,
+        ..Default::default()
+    };
+    let params = FetchParams {
+        fallbacks,
+        ..FetchParams::from_options(primary, method, timeout, options)
+    };
+    request_with_status(connection, http_client(), params, &SystemClock)
+        .await
+        .map(|(record, _)| record)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread::sleep,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    struct TestCleanup {
+        path: String,
+    }
+
+    impl Drop for TestCleanup {
+        fn drop(&mut self) {
+            // WAL mode keeps two files alongside the database
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{}{suffix}", self.path));
+            }
+        }
+    }
+
+    async fn mock_server(handler: impl Fn(&str) -> String + Send + Sync + 'static) -> String {
+        // serve each connection with the raw HTTP response built by handler
+        mock_server_bytes(move |raw| handler(raw).into_bytes()).await
+    }
+
+    async fn mock_server_bytes(
+        handler: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    ) -> String {
+        // as mock_server, for responses whose body isn't text
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0; 4096];
+                    loop {
+                        let n = socket.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                        let raw = String::from_utf8_lossy(&buf);
+                        let complete = raw.find("\r\n\r\n").is_some_and(|end| {
+                            let length = raw[..end]
+                                .lines()
+                                .find_map(|line| {
+                                    let (name, value) = line.split_once(':')?;
+                                    if name.eq_ignore_ascii_case("content-length") {
+                                        value.trim().parse().ok()
+                                    } else {
+                                        None
+                                    }
+                                })
+                                .unwrap_or(0);
+                            buf.len() >= end + 4 + length
+                        });
+                        if n == 0 || complete {
+                            break;
+                        }
+                    }
+                    let response = handler(&String::from_utf8_lossy(&buf));
+                    let _ = socket.write_all(&response).await;
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn test_record(url: &str, response: &str) -> Record {
+        Record {
+            request: url.to_string(),
+            final_url: url.to_string(),
+            method: "GET".to_string(),
+            body: None,
+            response: response.to_string(),
+            response_bytes: response.as_bytes().to_vec(),
+            content_type: None,
+            status: 200,
+            expires: i64::MAX,
+            cached: false,
+            fetched_at: now_millis(),
+            changed: None,
+            etag: None,
+            weak_etag: false,
+            location: None,
+            headers: Vec::new(),
+            stale: false,
+            revalidated: false,
+            tag: None,
+            fetch_duration_ms: None,
+            storage_failed: false,
+        }
+    }
+
+    async fn count_rows(db_client: &Client) -> i64 {
+        let query = "SELECT COUNT(*) FROM requests";
+        db_client
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_create_connection() {
+        let clean = TestCleanup {
+            path: "test".to_string(),
+        };
+        create_connection(clean.path.clone()).await.unwrap();
+        // a path that can't be opened is an error rather than a panic
+        let missing = create_connection("test_missing_dir/cache.db".to_string());
+        assert!(matches!(missing.await, Err(CacheError::Storage(_))));
+        let missing = create_connection_without_wal("test_missing_dir/cache.db".to_string());
+        assert!(missing.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_database_fails_to_open() {
+        let clean = TestCleanup {
+            path: "test_corrupt_database".to_string(),
+        };
+        // without WAL, every record is in the main file once the connection is closed
+        let db_client = create_connection_without_wal(clean.path.clone())
+            .await
+            .unwrap();
+        for i in 0..50 {
+            let record = test_record(&format!("http://a.test/{i}"), &"x".repeat(1000));
+            put(&db_client, record).await.unwrap();
+        }
+        db_client.close().await.unwrap();
+        // overwrite a page past the schema, so the file still opens as a database
+        let mut bytes = fs::read(&clean.path).unwrap();
+        bytes[8192..12288].fill(0xAB);
+        fs::write(&clean.path, bytes).unwrap();
+        let opened = create_connection(clean.path.clone()).await;
+        assert!(matches!(
+            opened,
+            Err(CacheError::Storage(async_sqlite::Error::Rusqlite(err)))
+                if err.sqlite_error_code() == Some(ErrorCode::DatabaseCorrupt)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_where_body() {
+        let clean = TestCleanup {
+            path: "test_invalidate_where_body".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        insert_record(
+            &db_client,
+            DEFAULT_TABLE,
+            test_record("http://a.test", "ok"),
+            "",
+            &[],
+        )
+        .await
+        .unwrap();
+        insert_record(
+            &db_client,
+            DEFAULT_TABLE,
+            test_record("http://b.test", "ERROR: upstream"),
+            "",
+            &[],
+        )
+        .await
+        .unwrap();
+        insert_record(
+            &db_client,
+            DEFAULT_TABLE,
+            test_record("http://c.test", "ERROR: again"),
+            "",
+            &[],
+        )
+        .await
+        .unwrap();
+        let deleted = invalidate_where_body(&db_client, |body| body.contains("ERROR"))
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+        assert_eq!(count_rows(&db_client).await, 1);
+        assert!(get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis()
+        )
+        .await
+        .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_request() {
+        let clean = TestCleanup {
+            path: "test_1".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let resp = request(
+            &db_client,
+            "http://example.com".to_string(),
+            "GET".to_string(),
+            10000,
+            Some(false),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!resp.cached);
+        let query = "SELECT COUNT(*) FROM requests";
+        let res = db_client
+            .conn(move |conn| conn.query_row(query, [], |row| Ok(row.get(0))))
+            .await
+            .unwrap();
+        assert!(res == Ok(1));
+        let resp = request(
+            &db_client,
+            "http://example.com".to_string(),
+            "GET".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(resp.cached);
+        let query = "SELECT COUNT(*) FROM requests";
+        let res = db_client
+            .conn(move |conn| conn.query_row(query, [], |row| Ok(row.get(0))))
+            .await
+            .unwrap();
+        assert!(res == Ok(1));
+        let resp = request(
+            &db_client,
+            "http://example.com".to_string(),
+            "GET".to_string(),
+            10000,
+            Some(true),
+            Some("dummy".to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(!resp.cached);
+    }
+
+    #[tokio::test]
+    async fn test_cache_request_timeout() {
+        let clean = TestCleanup {
+            path: "test_4".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let resp = request(
+            &db_client,
+            "http://example.com".to_string(),
+            "GET".to_string(),
+            1,
+            Some(false),
+            Some("dummy".to_string()),
+        );
+        assert!(!resp.await.unwrap().cached);
+        let query = "SELECT COUNT(*) FROM requests";
+        let res = db_client
+            .conn(move |conn| conn.query_row(query, [], |row| Ok(row.get(0))))
+            .await
+            .unwrap();
+        assert!(res == Ok(1));
+        let resp = request(
+            &db_client,
+            "http://example.com".to_string(),
+            "GET".to_string(),
+            1,
+            Some(false),
+            None,
+        );
+        assert!(resp.await.unwrap().cached);
+        let query = "SELECT COUNT(*) FROM requests";
+        let res = db_client
+            .conn(move |conn| conn.query_row(query, [], |row| Ok(row.get(0))))
+            .await
+            .unwrap();
+        assert!(res == Ok(1));
+        sleep(Duration::from_secs(1));
+        let resp = request(
+            &db_client,
+            "http://example.com".to_string(),
+            "GET".to_string(),
+            5,
+            Some(false),
+            None,
+        );
+        assert!(!resp.await.unwrap().cached);
+        let query = "SELECT COUNT(*) FROM requests";
+        let res = db_client
+            .conn(move |conn| conn.query_row(query, [], |row| Ok(row.get(0))))
+            .await
+            .unwrap();
+        assert!(res == Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_force_refresh_falls_back_to_stale() {
+        let clean = TestCleanup {
+            path: "test_force_refresh_stale".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // nothing listens on port 1, so the refresh fails to connect
+        let url = "http://127.0.0.1:1/".to_string();
+        let mut record = test_record(&url, "stale body");
+        record.expires = 1;
+        insert_record(&db_client, DEFAULT_TABLE, record, "", &[])
+            .await
+            .unwrap();
+        let resp = request_with_options(
+            &db_client,
+            url,
+            "GET".to_string(),
+            10000,
+            RequestOptions {
+                force_refresh: true,
+                stale_on_error: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.response, "stale body");
+        assert!(resp.cached);
+    }
+
+    #[tokio::test]
+    async fn test_breakdowns() {
+        let clean = TestCleanup {
+            path: "test_breakdowns".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        insert_record(
+            &db_client,
+            DEFAULT_TABLE,
+            test_record("http://a.test/1", ""),
+            "",
+            &[],
+        )
+        .await
+        .unwrap();
+        insert_record(
+            &db_client,
+            DEFAULT_TABLE,
+            test_record("http://a.test/2", ""),
+            "",
+            &[],
+        )
+        .await
+        .unwrap();
+        let mut record = test_record("http://b.test/1", "");
+        record.method = "POST".to_string();
+        insert_record(&db_client, DEFAULT_TABLE, record, "", &[])
+            .await
+            .unwrap();
+
+        let by_method = breakdown_by_method(&db_client).await.unwrap();
+        assert_eq!(by_method.len(), 2);
+        assert_eq!(by_method["GET"], 2);
+        assert_eq!(by_method["POST"], 1);
+
+        let by_host = breakdown_by_host(&db_client).await.unwrap();
+        assert_eq!(by_host.len(), 2);
+        assert_eq!(by_host["a.test"], 2);
+        assert_eq!(by_host["b.test"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_zero_timeout_is_not_cached() {
+        let clean = TestCleanup {
+            path: "test_zero_timeout".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = mock_server(|_| http_response("200 OK", "fresh")).await;
+        let resp = request(&db_client, url, "GET".to_string(), 0, None, None)
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "fresh");
+        assert!(!resp.cached);
+        assert_eq!(count_rows(&db_client).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_only_safe_methods_cached_by_default() {
+        let clean = TestCleanup {
+            path: "test_safe_methods".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = mock_server(|_| http_response("200 OK", "body")).await;
+        let resp = request(
+            &db_client,
+            url.clone(),
+            "GET".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!resp.cached);
+        assert_eq!(count_rows(&db_client).await, 1);
+        let resp = request(
+            &db_client,
+            url.clone(),
+            "POST".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!resp.cached);
+        assert_eq!(count_rows(&db_client).await, 1);
+        let resp = request_with_options(
+            &db_client,
+            url,
+            "POST".to_string(),
+            10000,
+            RequestOptions {
+                cache_unsafe: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!resp.cached);
+        assert_eq!(count_rows(&db_client).await, 2);
+    }
+
+    #[cfg(feature = "sql-trace")]
+    #[tokio::test]
+    async fn test_sql_trace() {
+        static STATEMENTS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+        fn capture(sql: &str, _elapsed: Duration) {
+            STATEMENTS.lock().unwrap().push(sql.to_string());
+        }
+
+        let clean = TestCleanup {
+            path: "test_sql_trace".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        set_sql_trace(&db_client, Some(capture)).await.unwrap();
+        insert_record(
+            &db_client,
+            DEFAULT_TABLE,
+            test_record("http://a.test", "traced"),
+            "",
+            &[],
+        )
+        .await
+        .unwrap();
+        get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis(),
+        )
+        .await
+        .unwrap();
+        let statements = STATEMENTS.lock().unwrap();
+        assert!(statements.iter().any(|sql| sql.starts_with("INSERT")));
+        assert!(statements.iter().any(|sql| sql.starts_with("SELECT")));
+    }
+
+    #[tokio::test]
+    async fn test_insert_retries_while_locked() {
+        let clean = TestCleanup {
+            path: "test_insert_locked".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // fail immediately on contention so only the retry loop can wait for the lock
+        db_client
+            .conn(|conn| conn.busy_timeout(Duration::ZERO))
+            .await
+            .unwrap();
+        let locker = create_connection(clean.path.clone()).await.unwrap();
+        locker
+            .conn(|conn| conn.execute_batch("BEGIN EXCLUSIVE;"))
+            .await
+            .unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            locker
+                .conn(|conn| conn.execute_batch("COMMIT;"))
+                .await
+                .unwrap();
+        });
+        insert_record(
+            &db_client,
+            DEFAULT_TABLE,
+            test_record("http://a.test", "locked"),
+            "",
+            &[],
+        )
+        .await
+        .unwrap();
+        release.await.unwrap();
+        assert_eq!(count_rows(&db_client).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_put_is_served_from_cache() {
+        let clean = TestCleanup {
+            path: "test_put".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = "http://127.0.0.1:1/".to_string();
+        put(&db_client, test_record(&url, "seeded")).await.unwrap();
+        let resp = request(&db_client, url, "GET".to_string(), 10000, None, None)
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "seeded");
+        assert!(resp.cached);
+    }
+
+    #[tokio::test]
+    async fn test_sub_second_expiry() {
+        let clean = TestCleanup {
+            path: "test_sub_second_expiry".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let mut record = test_record("http://a.test", "brief");
+        record.expires = now_millis() + 500;
+        put(&db_client, record).await.unwrap();
+        assert!(get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis()
+        )
+        .await
+        .is_some());
+        sleep(Duration::from_millis(600));
+        assert!(get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis()
+        )
+        .await
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_migrates_second_expiry() {
+        let clean = TestCleanup {
+            path: "test_migrate_seconds".to_string(),
+        };
+        let old = ClientBuilder::new()
+            .path(clean.path.clone())
+            .open()
+            .await
+            .unwrap();
+        old.conn(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE requests (request TEXT, method TEXT, response TEXT, expires INTEGER);
+                 INSERT INTO requests VALUES ('http://a.test', 'GET', 'old', 4102444800);",
+            )
+        })
+        .await
+        .unwrap();
+        old.close().await.unwrap();
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let record = get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(record.expires, 4102444800000);
+    }
+
+    #[tokio::test]
+    async fn test_migrates_oldest_schema_to_current() {
+        let clean = TestCleanup {
+            path: "test_migrate_oldest".to_string(),
+        };
+        let old = ClientBuilder::new()
+            .path(clean.path.clone())
+            .open()
+            .await
+            .unwrap();
+        old.conn(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE requests (request TEXT, method TEXT, response TEXT, expires INTEGER);
+                 INSERT INTO requests VALUES ('http://a.test', 'GET', 'superseded', 4102444800);
+                 INSERT INTO requests VALUES ('http://a.test', 'GET', 'kept', 4102444800);
+                 INSERT INTO requests VALUES ('http://b.test', 'GET', 'also kept', 4102444800);",
+            )
+        })
+        .await
+        .unwrap();
+        old.close().await.unwrap();
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let version: i64 = db_client
+            .conn(|conn| conn.query_row("PRAGMA user_version;", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(version, 22);
+        // duplicates from before the unique key keep only the newest row
+        assert_eq!(count_rows(&db_client).await, 2);
+        let record = get_cached(&db_client, "http://a.test".to_string(), "GET".to_string())
+            .await
+            .unwrap();
+        assert_eq!(record.response, "kept");
+        assert_eq!(record.response_bytes, b"kept");
+        assert_eq!(record.status, 200);
+        assert!(record.headers.is_empty());
+        assert!(record.tag.is_none());
+        assert!(!record.weak_etag);
+    }
+
+    #[tokio::test]
+    async fn test_stored_record_fields_round_trip() {
+        let db_client = create_memory_connection().await.unwrap();
+        let record = Record {
+            method: "DELETE".to_string(),
+            ..test_record("http://url.test/path", "body")
+        };
+        put(&db_client, record.clone()).await.unwrap();
+        let stored = get_cached(&db_client, record.request.clone(), "DELETE".to_string())
+            .await
+            .unwrap();
+        assert_eq!(stored.request, "http://url.test/path");
+        assert_eq!(stored.method, "DELETE");
+        assert_eq!(stored.response, "body");
+        assert_eq!(stored.expires, record.expires);
+        assert_eq!(stored.fetched_at, record.fetched_at);
+    }
+
+    #[test]
+    fn test_record_age_and_lifetime() {
+        let mut record = test_record("http://a.test", "");
+        record.fetched_at = 1_000_000;
+        record.expires = 1_060_000;
+        assert_eq!(record.freshness_lifetime(), Duration::from_secs(60));
+        record.fetched_at = now_millis() - 5_000;
+        let age = record.age();
+        assert!(age >= Duration::from_secs(5) && age < Duration::from_secs(6));
+        assert_eq!(record.age_secs(), 5);
+        record.expires = now_millis() + 30_500;
+        assert_eq!(record.ttl_remaining_secs(), 30);
+        record.fetched_at = now_millis() + 5_000;
+        assert_eq!(record.age(), Duration::ZERO);
+        record.expires = record.fetched_at - 1;
+        assert_eq!(record.freshness_lifetime(), Duration::ZERO);
+        record.expires = now_millis() - 1;
+        assert_eq!(record.ttl_remaining_secs(), 0);
+    }
+
+    #[test]
+    fn test_record_freshness() {
+        let mut record = test_record("http://a.test", "");
+        record.expires = 1_060_000;
+        assert!(!record.is_expired(1_059_999));
+        assert!(record.is_expired(1_060_000));
+        record.expires = now_millis() + 30_500;
+        assert!(record.is_fresh());
+        assert_eq!(record.seconds_until_expiry(), 30);
+        record.expires = now_millis() - 1_500;
+        assert!(!record.is_fresh());
+        assert_eq!(record.seconds_until_expiry(), -2);
+    }
+
+    #[tokio::test]
+    async fn test_migration_hook() {
+        let clean = TestCleanup {
+            path: "test_migration_hook".to_string(),
+        };
+        let db_client = create_connection_with_migration(clean.path.clone(), |conn| {
+            conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_app_expires ON requests(expires);")
+        })
+        .await
+        .unwrap();
+        let query =
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'idx_app_expires';";
+        let indexes: i64 = db_client
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(indexes, 1);
+        // a failing hook fails opening the database
+        let failed = create_connection_with_migration(clean.path.clone(), |conn| {
+            conn.execute_batch("CREATE INDEX idx_app_expires ON requests(expires);")
+        });
+        assert!(matches!(failed.await, Err(CacheError::Storage(_))));
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_body_detected_by_digest() {
+        let clean = TestCleanup {
+            path: "test_digest".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let body = "x".repeat(1 << 20);
+        let mut record = test_record("http://a.test", &body);
+        record.expires = now_millis() + 10_000;
+        assert!(
+            insert_record(&db_client, DEFAULT_TABLE, record.clone(), "", &[])
+                .await
+                .unwrap()
+        );
+        record.expires = now_millis() + 20_000;
+        assert!(
+            !insert_record(&db_client, DEFAULT_TABLE, record.clone(), "", &[])
+                .await
+                .unwrap()
+        );
+        let stored = get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stored.expires, record.expires);
+        assert_eq!(count_rows(&db_client).await, 1);
+        record.response.push('y');
+        record.response_bytes.push(b'y');
+        assert!(insert_record(&db_client, DEFAULT_TABLE, record, "", &[])
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_cached_under_primary() {
+        let clean = TestCleanup {
+            path: "test_fallbacks".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let primary = "http://127.0.0.1:1/".to_string();
+        let fallback = mock_server(|_| http_response("200 OK", "from fallback")).await;
+        let resp = request_with_fallbacks(
+            &db_client,
+            primary.clone(),
+            &["http://127.0.0.1:1/other".to_string(), fallback],
+            "GET".to_string(),
+            10000,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.response, "from fallback");
+        assert_eq!(resp.request, primary);
+        assert_eq!(resp.final_url, primary);
+        assert!(!resp.cached);
+        let resp = request_with_fallbacks(&db_client, primary, &[], "GET".to_string(), 10000, None)
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "from fallback");
+        assert_eq!(resp.final_url, resp.request);
+        assert!(resp.cached);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_after_error_status() {
+        let db_client = create_memory_connection().await.unwrap();
+        let primary = mock_server(|_| http_response("503 Service Unavailable", "down")).await;
+        let fallback = mock_server(|_| http_response("200 OK", "from fallback")).await;
+        let resp = request_with_fallbacks(
+            &db_client,
+            primary.clone(),
+            std::slice::from_ref(&fallback),
+            "GET".to_string(),
+            10000,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.response, "from fallback");
+        // when every url fails the last response is returned, and a 5xx isn't stored
+        let db_client = create_memory_connection().await.unwrap();
+        let resp = request_with_fallbacks(
+            &db_client,
+            primary.clone(),
+            std::slice::from_ref(&primary),
+            "GET".to_string(),
+            10000,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status, 503);
+        assert_eq!(count_rows(&db_client).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_cache_get_with_fallbacks() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let primary = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("502 Bad Gateway", "")
+        })
+        .await;
+        let fallback = mock_server(|_| http_response("200 OK", "from fallback")).await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .retry(RetryPolicy::new(2, Duration::from_millis(1)))
+            .build()
+            .await
+            .unwrap();
+        let resp = cache
+            .get_with_fallbacks(&primary, &["http://127.0.0.1:1/".to_string(), fallback])
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "from fallback");
+        assert_eq!(resp.request, primary);
+        // the primary was retried before falling back
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        let resp = cache.get(&primary).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.response, "from fallback");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_before() {
+        let clean = TestCleanup {
+            path: "test_invalidate_before".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let mut record = test_record("http://old.test", "old");
+        record.fetched_at = now_millis() - 1_000;
+        put(&db_client, record).await.unwrap();
+        invalidate_before(&db_client, now_millis()).await.unwrap();
+        let mut record = test_record("http://new.test", "new");
+        record.fetched_at = now_millis() + 1_000;
+        put(&db_client, record).await.unwrap();
+        assert!(get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://old.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis()
+        )
+        .await
+        .is_none());
+        assert!(get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://new.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis()
+        )
+        .await
+        .is_some());
+        assert_eq!(count_rows(&db_client).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_verify_all_flags_corruption() {
+        let clean = TestCleanup {
+            path: "test_verify_all".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        put(&db_client, test_record("http://a.test", "intact"))
+            .await
+            .unwrap();
+        put(&db_client, test_record("http://b.test", "soon corrupt"))
+            .await
+            .unwrap();
+        let query = "UPDATE requests SET response = 'garbage', response_bytes = CAST('garbage' AS BLOB) WHERE request = 'http://b.test';";
+        db_client
+            .conn(move |conn| conn.execute(query, []))
+            .await
+            .unwrap();
+        let report = verify_all(&db_client, false).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report.corrupt,
+            vec![("http://b.test".to_string(), "GET".to_string())]
+        );
+        assert_eq!(report.deleted, 0);
+        let report = verify_all(&db_client, true).await.unwrap();
+        assert_eq!(report.deleted, 1);
+        assert_eq!(count_rows(&db_client).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_verify_all_flags_missing_blobs() {
+        let db_client = create_memory_connection().await.unwrap();
+        set_deduplication(&db_client, true).await.unwrap();
+        put(&db_client, test_record("http://a.test", "intact"))
+            .await
+            .unwrap();
+        put(&db_client, test_record("http://b.test", "soon missing"))
+            .await
+            .unwrap();
+        let query = "DELETE FROM blobs WHERE data = CAST('soon missing' AS BLOB);";
+        let removed = db_client.conn(move |conn| conn.execute(query, [])).await;
+        assert_eq!(removed.unwrap(), 1);
+        let report = verify_all(&db_client, false).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report.corrupt,
+            vec![("http://b.test".to_string(), "GET".to_string())]
+        );
+        let report = verify_all(&db_client, true).await.unwrap();
+        assert_eq!(report.deleted, 1);
+        assert_eq!(count_rows(&db_client).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_detailed() {
+        let clean = TestCleanup {
+            path: "test_request_detailed".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = mock_server(|_| http_response("200 OK", "twelve bytes")).await;
+        let outcome = request_detailed(
+            &db_client,
+            url.clone(),
+            "GET".to_string(),
+            10000,
+            RequestOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, CacheStatus::Miss);
+        assert!(outcome.from_network);
+        assert_eq!(outcome.bytes, 12);
+        assert!(!outcome.record.cached);
+        let outcome = request_detailed(
+            &db_client,
+            url,
+            "GET".to_string(),
+            10000,
+            RequestOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        assert!(!outcome.from_network);
+        assert_eq!(outcome.bytes, 12);
+        assert!(outcome.record.cached);
+    }
+
+    #[tokio::test]
+    async fn test_method_is_sent_and_normalised() {
+        let clean = TestCleanup {
+            path: "test_method_dispatch".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // echo the request method back as the body
+        let url = mock_server(|raw| {
+            let method = raw.split(' ').next().unwrap_or_default();
+            http_response("200 OK", method)
+        })
+        .await;
+        let resp = request_with_options(
+            &db_client,
+            url.clone(),
+            "delete".to_string(),
+            10000,
+            RequestOptions {
+                cache_unsafe: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.response, "DELETE");
+        assert_eq!(resp.method, "DELETE");
+        let resp = request(
+            &db_client,
+            url.clone(),
+            " get ".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.response, "GET");
+        assert!(!resp.cached);
+        let resp = request(&db_client, url, "GET".to_string(), 10000, None, None)
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "GET");
+        assert!(resp.cached);
+        let by_method = breakdown_by_method(&db_client).await.unwrap();
+        assert_eq!(by_method["GET"], 1);
+        assert_eq!(by_method["DELETE"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method_is_an_error() {
+        let clean = TestCleanup {
+            path: "test_unknown_method".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let resp = request(
+            &db_client,
+            "http://127.0.0.1:1/".to_string(),
+            "FETCH".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(resp, Err(CacheError::InvalidMethod(method)) if method == "FETCH"));
+    }
+
+    #[tokio::test]
+    async fn test_method_spellings_share_records() {
+        let db_client = create_memory_connection().await.unwrap();
+        // nothing listens on port 1, so only a cache hit can answer
+        let url = "http://127.0.0.1:1/".to_string();
+        let mut record = test_record(&url, "stored");
+        record.method = "get".to_string();
+        put(&db_client, record).await.unwrap();
+        let cached = get_cached(&db_client, url.clone(), "GET".to_string()).await;
+        assert_eq!(cached.unwrap().method, "GET");
+        let found = get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            url.clone(),
+            " Get ".to_string(),
+            String::new(),
+            &[],
+            now_millis(),
+        );
+        assert!(found.await.is_some());
+        let resp = get(&db_client, url, 60).await.unwrap();
+        assert!(resp.cached && resp.response == "stored");
+        assert_eq!(count_rows(&db_client).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_host_is_an_error() {
+        let clean = TestCleanup {
+            path: "test_unreachable".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let resp = request(
+            &db_client,
+            "http://127.0.0.1:1/".to_string(),
+            "GET".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(resp, Err(CacheError::Http(_))));
+        assert_eq!(count_rows(&db_client).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_user_agent_is_an_error() {
+        let clean = TestCleanup {
+            path: "test_invalid_user_agent".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let resp = request(
+            &db_client,
+            "http://127.0.0.1:1/".to_string(),
+            "GET".to_string(),
+            10000,
+            None,
+            Some("bad\nagent".to_string()),
+        )
+        .await;
+        assert!(matches!(resp, Err(CacheError::InvalidHeader(_))));
+    }
+
+    #[tokio::test]
+    async fn test_status_is_stored_and_errors_optionally_skipped() {
+        let clean = TestCleanup {
+            path: "test_status".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = mock_server(|_| http_response("404 Not Found", "no such page")).await;
+        let resp = request_with_options(
+            &db_client,
+            url.clone(),
+            "GET".to_string(),
+            10000,
+            RequestOptions {
+                skip_error_status: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status, 404);
+        assert_eq!(count_rows(&db_client).await, 0);
+        let resp = request(
+            &db_client,
+            url.clone(),
+            "GET".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status, 404);
+        assert_eq!(count_rows(&db_client).await, 1);
+        let cached = get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            url,
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cached.status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_never_cached() {
+        let db_client = create_memory_connection().await.unwrap();
+        let url = mock_server(|_| http_response("503 Service Unavailable", "try later")).await;
+        // no negative TTL or skip_error_status is needed to keep a 5xx out
+        let resp = request(&db_client, url, "GET".to_string(), 10000, None, None)
+            .await
+            .unwrap();
+        assert_eq!(resp.status, 503);
+        assert_eq!(count_rows(&db_client).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_body_is_sent_and_keyed() {
+        let clean = TestCleanup {
+            path: "test_request_body".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // echo the request body back
+        let url = mock_server(|raw| {
+            let body = raw.split_once("\r\n\r\n").map(|(_, body)| body);
+            http_response("200 OK", &format!("searched {}", body.unwrap_or_default()))
+        })
+        .await;
+        for (query, cached) in [("cats", false), ("dogs", false), ("cats", true)] {
+            let resp = request_with_options(
+                &db_client,
+                url.clone(),
+                "POST".to_string(),
+                10000,
+                RequestOptions {
+                    cache_unsafe: true,
+                    body: Some(query.to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(resp.response, format!("searched {query}"));
+            assert_eq!(resp.cached, cached);
+            // fetched or cached, the record says which body it answers
+            assert_eq!(resp.body.as_deref(), Some(query));
+        }
+        assert_eq!(count_rows(&db_client).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_headers() {
+        let clean = TestCleanup {
+            path: "test_request_headers".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // echo the Authorization header back
+        let url = mock_server(|raw| {
+            let auth = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("authorization: "))
+                .unwrap_or("none");
+            http_response("200 OK", auth)
+        })
+        .await;
+        let headers = vec![("Authorization".to_string(), "Bearer abc".to_string())];
+        let resp = request_with_options(
+            &db_client,
+            url.clone(),
+            "GET".to_string(),
+            10000,
+            RequestOptions {
+                headers: Some(headers),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.response, "Bearer abc");
+        // headers aren't part of the cache key unless Vary names them
+        let resp = request(
+            &db_client,
+            url.clone(),
+            "GET".to_string(),
+            10000,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(resp.cached);
+        let bad = vec![("Bad Name".to_string(), "x".to_string())];
+        let err = request_with_options(
+            &db_client,
+            url,
+            "GET".to_string(),
+            10000,
+            RequestOptions {
+                force_refresh: true,
+                headers: Some(bad),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, CacheError::InvalidHeaderName(_)));
+    }
+
+    #[tokio::test]
+    async fn test_request_cache_builder() {
+        let clean = TestCleanup {
+            path: "test_request_cache_builder".to_string(),
+        };
+        let url = mock_server(|raw| {
+            let agent = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("user-agent: "))
+                .unwrap_or("none");
+            let body = raw.split_once("\r\n\r\n").map(|(_, body)| body);
+            http_response("200 OK", &format!("{agent} {}", body.unwrap_or_default()))
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .user_agent("builder-test")
+            .default_timeout(60)
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get(&url).await.unwrap();
+        assert_eq!(resp.response, "builder-test ");
+        assert!(!resp.cached);
+        assert!(cache.request("get", &url).await.unwrap().cached);
+        // POST isn't cached, so the body always reaches the server
+        let resp = cache.post(&url, "payload").await.unwrap();
+        assert_eq!(resp.response, "builder-test payload");
+        assert_eq!(count_rows(cache.connection()).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_misses_share_connection() {
+        let clean = TestCleanup {
+            path: "test_misses_share_connection".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // a keep-alive server counting the connections it accepts
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut chunk = [0; 4096];
+                    while let Ok(n) = socket.read(&mut chunk).await {
+                        if n == 0 {
+                            break;
+                        }
+                        let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                        if socket.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        for path in ["a", "b", "c"] {
+            let resp = request(
+                &db_client,
+                format!("http://{addr}/{path}"),
+                "GET".to_string(),
+                10000,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(!resp.cached);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let clean = TestCleanup {
+            path: "test_request_timeout".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // accept connections but never answer them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            loop {
+                sockets.push(listener.accept().await.unwrap());
+            }
+        });
+        let err = request_with_options(
+            &db_client,
+            format!("http://{addr}"),
+            "GET".to_string(),
+            10000,
+            RequestOptions {
+                request_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, CacheError::Timeout(_)));
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        // a listener that never accepts, with its backlog filled, so further connections
+        // can't be established
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let _listener = socket.listen(0).unwrap();
+        let mut queued = Vec::new();
+        loop {
+            let connect = tokio::net::TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(200), connect).await {
+                Ok(stream) => queued.push(stream.unwrap()),
+                Err(_) => break,
+            }
+        }
+        let cache = RequestCache::builder()
+            .in_memory()
+            .request_timeout(Duration::from_secs(30))
+            .connect_timeout(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+        let started = std::time::Instant::now();
+        let err = cache.get(&format!("http://{addr}")).await.unwrap_err();
+        assert!(matches!(err, CacheError::Shared(err) if matches!(*err, CacheError::Timeout(_))));
+        // well within the request timeout
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_cache_headers_set_expiry() {
+        let clean = TestCleanup {
+            path: "test_cache_headers".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = mock_server(|raw| {
+            let header = if raw.starts_with("GET /max-age") {
+                "Cache-Control: public, max-age=5\r\n"
+            } else if raw.starts_with("GET /expires") {
+                "Expires: Thu, 01 Jan 1970 00:00:00 GMT\r\n"
+            } else if raw.starts_with("GET /no-store") {
+                "Cache-Control: no-store\r\n"
+            } else {
+                ""
+            };
+            format!("HTTP/1.1 200 OK\r\n{header}Content-Length: 2\r\nConnection: close\r\n\r\nok")
+        })
+        .await;
+        for (path, lifetime) in [("max-age", 5000), ("plain", 60000)] {
+            let resp = request_with_options(
+                &db_client,
+                format!("{url}/{path}"),
+                "GET".to_string(),
+                60,
+                RequestOptions {
+                    use_cache_headers: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(resp.expires - resp.fetched_at, lifetime);
+        }
+        // an Expires in the past and no-store both skip the cache
+        for path in ["expires", "no-store"] {
+            let resp = request_with_options(
+                &db_client,
+                format!("{url}/{path}"),
+                "GET".to_string(),
+                60,
+                RequestOptions {
+                    use_cache_headers: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert!(resp.changed.is_none());
+        }
+        assert_eq!(count_rows(&db_client).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_etag_revalidation() {
+        let clean = TestCleanup {
+            path: "test_etag_revalidation".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = mock_server(|raw| {
+            if raw.contains("if-none-match: \"v1\"") {
+                "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody"
+                    .to_string()
+            }
+        })
+        .await;
+        let fetch = || {
+            request_detailed(
+                &db_client,
+                url.clone(),
+                "GET".to_string(),
+                60,
+                RequestOptions::default(),
+            )
+        };
+        let outcome = fetch().await.unwrap();
+        assert_eq!(outcome.status, CacheStatus::Miss);
+        assert_eq!(outcome.record.etag.as_deref(), Some("\"v1\""));
+        assert!(!outcome.record.revalidated);
+        // expire the record, the 304 then keeps its body; wait first so the epoch is
+        // after fetched_at even when the fetch took under a millisecond
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        invalidate_before(&db_client, now_millis()).await.unwrap();
+        let outcome = fetch().await.unwrap();
+        assert_eq!(outcome.status, CacheStatus::Revalidated);
+        assert_eq!(outcome.record.response, "body");
+        assert_eq!(outcome.record.status, 200);
+        assert!(outcome.record.revalidated);
+        let outcome = fetch().await.unwrap();
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        assert!(!outcome.record.revalidated);
+    }
+
+    #[tokio::test]
+    async fn test_weak_etag_revalidation() {
+        let clean = TestCleanup {
+            path: "test_weak_etag_revalidation".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        // the 304's etag is picked by the request path, the stored one is always weak
+        let url = mock_server(|raw| {
+            let path = raw.split_whitespace().nth(1).unwrap_or_default();
+            if raw.contains("if-none-match: W/\"v1\"") {
+                let etag = if path == "/strong" { "\"v1\"" } else { "W/\"v1\"" };
+                format!("HTTP/1.1 304 Not Modified\r\nETag: {etag}\r\nConnection: close\r\n\r\n")
+            } else {
+                "HTTP/1.1 200 OK\r\nETag: W/\"v1\"\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody"
+                    .to_string()
+            }
+        })
+        .await;
+        for (path, matches) in [("/weak", true), ("/strong", false)] {
+            let url = format!("{url}{path}");
+            let fetch = || {
+                request_detailed(
+                    &db_client,
+                    url.clone(),
+                    "GET".to_string(),
+                    60,
+                    RequestOptions::default(),
+                )
+            };
+            let outcome = fetch().await.unwrap();
+            assert_eq!(outcome.record.etag.as_deref(), Some("W/\"v1\""));
+            assert!(outcome.record.weak_etag);
+            let stored = get_cached(&db_client, url.clone(), "GET".to_string()).await;
+            assert!(stored.unwrap().weak_etag);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            invalidate_before(&db_client, now_millis()).await.unwrap();
+            let outcome = fetch().await.unwrap();
+            // a strong etag never matches a weak one, so that 304 can't be used and the body
+            // is fetched again in full
+            assert_eq!(outcome.record.revalidated, matches, "{path}");
+            assert_eq!(outcome.record.status, 200);
+            assert_eq!(outcome.record.response, "body");
+            assert!(outcome.record.weak_etag);
+        }
+    }
+
+    #[test]
+    fn test_record_metadata_accessors() {
+        let mut record = test_record("http://a.test", "ok");
+        assert_eq!(record.content_type(), None);
+        assert_eq!(record.etag(), None);
+        assert_eq!(record.last_modified(), None);
+        assert_eq!(record.cache_control(), CacheControl::default());
+        record.content_type = Some("Application/JSON; charset=utf-8".to_string());
+        assert_eq!(record.content_type(), Some("Application/JSON"));
+        record.etag = Some("W/\"v1\"".to_string());
+        assert_eq!(record.etag(), Some("v1"));
+        record.etag = Some("\"v2\"".to_string());
+        assert_eq!(record.etag(), Some("v2"));
+        record.headers = vec![
+            (
+                "Last-Modified".to_string(),
+                "Wed, 21 Oct 2015 07:28:00 GMT".to_string(),
+            ),
+            (
+                "Cache-Control".to_string(),
+                "public, max-age=60, must-revalidate".to_string(),
+            ),
+            (
+                "cache-control".to_string(),
+                "s-maxage=\"120\", stale-if-error=5, private=\"Set-Cookie\", bogus".to_string(),
+            ),
+        ];
+        let modified = std::time::UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        assert_eq!(record.last_modified(), Some(modified));
+        let cache_control = record.cache_control();
+        assert_eq!(
+            cache_control,
+            CacheControl {
+                max_age: Some(Duration::from_secs(60)),
+                s_maxage: Some(Duration::from_secs(120)),
+                stale_if_error: Some(Duration::from_secs(5)),
+                private: true,
+                public: true,
+                must_revalidate: true,
+                ..Default::default()
+            }
+        );
+        record.headers = vec![("Last-Modified".to_string(), "yesterday".to_string())];
+        assert_eq!(record.last_modified(), None);
+    }
+
+    #[test]
+    fn test_etag_comparison() {
+        let with_etag = |etag: &str| Record {
+            etag: Some(etag.to_string()),
+            weak_etag: etag.starts_with("W/"),
+            ..test_record("http://a.test", "")
+        };
+        let cases = [
+            ("\"1\"", "\"1\"", true),
+            ("\"1\"", "W/\"1\"", false),
+            ("W/\"1\"", "\"1\"", true),
+            ("W/\"1\"", "W/\"1\"", true),
+            ("W/\"1\"", "W/\"2\"", false),
+            ("\"1\"", "\"2\"", false),
+        ];
+        for (received, stored, expected) in cases {
+            let confirmed = confirms(&with_etag(received), &with_etag(stored));
+            assert_eq!(confirmed, expected, "{received} against {stored}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_last_modified_revalidation() {
+        const MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let conditional = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = conditional.clone();
+        let url = mock_server(move |raw| {
+            let raw = raw.to_ascii_lowercase();
+            let modified = raw.contains(&format!("if-modified-since: {}", MODIFIED.to_ascii_lowercase()));
+            seen.lock().unwrap().push((modified, raw.contains("if-none-match: \"v1\"")));
+            let etag = if raw.starts_with("get /both") { "ETag: \"v1\"\r\n" } else { "" };
+            if modified {
+                "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+            } else {
+                format!("HTTP/1.1 200 OK\r\nLast-Modified: {MODIFIED}\r\n{etag}Content-Length: 4\r\nConnection: close\r\n\r\nbody")
+            }
+        })
+        .await;
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let cache = RequestCache::builder()
+            .in_memory()
+            .default_timeout(60)
+            .clock(clock.clone())
+            .build()
+            .await
+            .unwrap();
+        for path in ["modified", "both"] {
+            let url = format!("{url}/{path}");
+            clock.set(1_000_000);
+            assert!(!cache.get(&url).await.unwrap().revalidated);
+            clock.advance(Duration::from_secs(61));
+            // the 304 keeps the stored body and starts a new lifetime
+            let resp = cache.get(&url).await.unwrap();
+            assert!(resp.revalidated);
+            assert_eq!(resp.response, "body");
+            assert_eq!(resp.expires, 1_121_000);
+        }
+        // only a record with an ETag sends If-None-Match as well
+        let conditional = conditional.lock().unwrap();
+        assert_eq!(
+            *conditional,
+            [(false, false), (true, false), (false, false), (true, true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_headers_are_stored() {
+        let url = mock_server(|_| {
+            "HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nX-Request-Id: 7\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+                .to_string()
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let fetched = cache.get(&url).await.unwrap();
+        let cached = cache.get(&url).await.unwrap();
+        assert!(cached.cached);
+        assert_eq!(cached.headers, fetched.headers);
+        let cookies: Vec<_> = cached
+            .headers
+            .iter()
+            .filter(|(name, _)| name == "set-cookie")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(cached.header("X-Request-Id"), Some("7"));
+    }
+
+    #[tokio::test]
+    async fn test_vary_keeps_variants_apart() {
+        let clean = TestCleanup {
+            path: "test_vary_keeps_variants_apart".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let url = mock_server(|raw| {
+            let accept = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("accept: "))
+                .unwrap_or_default();
+            format!(
+                "HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{accept}",
+                accept.len()
+            )
+        })
+        .await;
+        let fetch = |accept: &str| {
+            request_with_options(
+                &db_client,
+                url.clone(),
+                "GET".to_string(),
+                60,
+                RequestOptions {
+                    headers: Some(vec![("Accept".to_string(), accept.to_string())]),
+                    ..Default::default()
+                },
+            )
+        };
+        for cached in [false, true] {
+            for accept in ["application/json", "text/html"] {
+                let resp = fetch(accept).await.unwrap();
+                assert_eq!(resp.response, accept);
+                assert_eq!(resp.cached, cached);
+            }
+        }
+        assert_eq!(count_rows(&db_client).await, 2);
+        // a request matching no stored variant is a miss
+        assert!(!fetch("text/plain").await.unwrap().cached);
+    }
+
+    #[tokio::test]
+    async fn test_binary_body_round_trips() {
+        let clean = TestCleanup {
+            path: "test_binary_body".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let body: Vec<u8> = vec![0x89, b'P', b'N', b'G', 0xff, 0x00, 0xfe];
+        let expected = body.clone();
+        // mock_server only builds text responses, so serve the bytes by hand
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut chunk = [0; 4096];
+            let _ = socket.read(&mut chunk).await.unwrap();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+        });
+        for cached in [false, true] {
+            let resp = request(
+                &db_client,
+                format!("http://{addr}"),
+                "GET".to_string(),
+                60,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(resp.cached, cached);
+            assert_eq!(resp.response_bytes, expected);
+            assert_eq!(resp.content_type.as_deref(), Some("image/png"));
+            assert!(resp.text().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired() {
+        let clean = TestCleanup {
+            path: "test_purge_expired".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        for url in ["http://a.test", "http://b.test", "http://c.test"] {
+            let mut record = test_record(url, "short lived");
+            record.expires = now_millis() + 50;
+            put(&db_client, record).await.unwrap();
+        }
+        assert_eq!(purge_expired(&db_client).await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(purge_expired(&db_client).await.unwrap(), 3);
+        assert_eq!(count_rows(&db_client).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_records_survive_purge_and_eviction() {
+        let db_client = create_memory_connection().await.unwrap();
+        for url in ["http://a.test", "http://b.test"] {
+            let mut record = test_record(url, "expired");
+            record.expires = now_millis() - 1;
+            put(&db_client, record).await.unwrap();
+        }
+        let pin_a = pin(&db_client, "http://a.test".to_string(), "get".to_string());
+        assert!(pin_a.await.unwrap());
+        let missing = pin(&db_client, "http://z.test".to_string(), "GET".to_string());
+        assert!(!missing.await.unwrap());
+        assert_eq!(purge_expired(&db_client).await.unwrap(), 1);
+        let stored = db_client.get_stale_record("http://a.test", "GET", "", &[]);
+        assert!(stored
+            .await
+            .is_some_and(|record| record.response == "expired"));
+        // replacing a pinned record keeps it pinned
+        put(&db_client, test_record("http://a.test", "refreshed"))
+            .await
+            .unwrap();
+        // the pinned record counts towards max_entries, but the unpinned are evicted instead
+        set_max_entries(&db_client, Some(2)).await.unwrap();
+        for url in ["http://b.test", "http://c.test"] {
+            put(&db_client, test_record(url, "body")).await.unwrap();
+        }
+        assert_eq!(count_rows(&db_client).await, 2);
+        let stored = get_if_cached(&db_client, "http://a.test".to_string()).await;
+        assert!(stored.is_some_and(|record| record.response == "refreshed"));
+        let unpin_a = unpin(&db_client, "http://a.test".to_string(), "GET".to_string());
+        assert!(unpin_a.await.unwrap());
+        put(&db_client, test_record("http://d.test", "body"))
+            .await
+            .unwrap();
+        assert!(get_if_cached(&db_client, "http://a.test".to_string())
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_clear_cache() {
+        let clean = TestCleanup {
+            path: "test_clear_cache".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        for url in ["http://a.test", "http://b.test", "http://c.test"] {
+            put(&db_client, test_record(url, "cleared")).await.unwrap();
+        }
+        assert_eq!(clear_cache(&db_client).await.unwrap(), 3);
+        assert_eq!(count_rows(&db_client).await, 0);
+        vacuum(&db_client).await.unwrap();
+        // the table is still there to cache into
+        put(&db_client, test_record("http://a.test", "again"))
+            .await
+            .unwrap();
+        assert_eq!(count_rows(&db_client).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge_where() {
+        let db_client = create_memory_connection().await.unwrap();
+        let urls = [
+            "http://a.test/small",
+            "http://A.test:8080/large",
+            "http://b.test/large",
+            "http://sub.a.test/large",
+            "http://b.test/?next=http://a.test/",
+        ];
+        for url in urls {
+            let body = if url.ends_with("large") {
+                "x".repeat(1000)
+            } else {
+                "x".to_string()
+            };
+            put(&db_client, test_record(url, &body)).await.unwrap();
+        }
+        let large_on_a = PurgeCriteria::new().host("a.TEST").larger_than(100);
+        assert_eq!(purge_where(&db_client, large_on_a).await.unwrap(), 1);
+        let on_a = PurgeCriteria::new().host("a.test");
+        assert_eq!(purge_where(&db_client, on_a).await.unwrap(), 1);
+        let old = PurgeCriteria::new().older_than(Duration::from_secs(60));
+        assert_eq!(purge_where(&db_client, old).await.unwrap(), 0);
+        let query = "SELECT request FROM requests ORDER BY rowid;";
+        let left = db_client
+            .conn(move |conn| {
+                let mut stmt = conn.prepare(query)?;
+                let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                rows.collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .unwrap();
+        assert_eq!(left, urls[2..]);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_export_import_json() {
+        let db_client = create_memory_connection().await.unwrap();
+        put(&db_client, test_record("http://a.test", "a"))
+            .await
+            .unwrap();
+        let mut posted = test_record("http://b.test", "b");
+        posted.method = "POST".to_string();
+        insert_record(&db_client, DEFAULT_TABLE, posted, "query", &[])
+            .await
+            .unwrap();
+        let mut varied = test_record("http://c.test", "c");
+        varied.headers = vec![("vary".to_string(), "Accept".to_string())];
+        let accept = [("accept".to_string(), "text/plain".to_string())];
+        insert_record(&db_client, DEFAULT_TABLE, varied, "", &accept)
+            .await
+            .unwrap();
+        let mut expired = test_record("http://d.test", "d");
+        expired.expires = now_millis() - 1;
+        put(&db_client, expired).await.unwrap();
+        let lookups = [
+            ("http://a.test", "GET", "", &[][..]),
+            ("http://b.test", "POST", "query", &[][..]),
+            ("http://c.test", "GET", "", &accept[..]),
+        ];
+        let mut before = Vec::new();
+        for (url, method, body, headers) in lookups {
+            before.push(
+                db_client
+                    .get_record(url, method, body, headers)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let dump = export_json(&db_client).await.unwrap();
+        clear_cache(&db_client).await.unwrap();
+        assert_eq!(import_json(&db_client, &dump).await.unwrap(), 3);
+        assert_eq!(count_rows(&db_client).await, 3);
+        for ((url, method, body, headers), before) in lookups.into_iter().zip(before) {
+            let after = db_client.get_record(url, method, body, headers).await;
+            assert_eq!(after, Some(before));
+        }
+        // importing again replaces rather than duplicates
+        import_json(&db_client, &dump).await.unwrap();
+        assert_eq!(count_rows(&db_client).await, 3);
+        let err = import_json(&db_client, "not json").await.unwrap_err();
+        assert!(matches!(err, CacheError::Deserialize(..)));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_export_import_json_through_cache_table() {
+        let clean = TestCleanup {
+            path: "test_export_table".to_string(),
+        };
+        let cache = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .table_name("external_api")
+            .build()
+            .await
+            .unwrap();
+        cache.put(test_record("http://a.test", "a")).await.unwrap();
+        let dump = cache.export_json().await.unwrap();
+        // the default table has nothing to export, and nothing is imported into it
+        assert_eq!(export_json(cache.connection()).await.unwrap(), "[]");
+        cache.clear().await.unwrap();
+        assert_eq!(cache.import_json(&dump).await.unwrap(), 1);
+        assert_eq!(cache.len().await.unwrap(), 1);
+        assert_eq!(cache_len(cache.connection()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_entries_evicts_oldest() {
+        let clean = TestCleanup {
+            path: "test_max_entries".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        for url in ["http://a.test", "http://b.test", "http://c.test"] {
+            put(&db_client, test_record(url, "body")).await.unwrap();
+        }
+        set_max_entries(&db_client, Some(2)).await.unwrap();
+        assert_eq!(count_rows(&db_client).await, 2);
+        put(&db_client, test_record("http://d.test", "body"))
+            .await
+            .unwrap();
+        assert_eq!(count_rows(&db_client).await, 2);
+        for (url, kept) in [
+            ("http://b.test", false),
+            ("http://c.test", true),
+            ("http://d.test", true),
+        ] {
+            let record = get_record(
+                &db_client,
+                DEFAULT_TABLE,
+                url.to_string(),
+                "GET".to_string(),
+                String::new(),
+                &[],
+                now_millis(),
+            )
+            .await;
+            assert_eq!(record.is_some(), kept);
+        }
+        set_max_entries(&db_client, None).await.unwrap();
+        put(&db_client, test_record("http://e.test", "body"))
+            .await
+            .unwrap();
+        assert_eq!(count_rows(&db_client).await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_track_access_evicts_least_recently_used() {
+        let clean = TestCleanup {
+            path: "test_track_access".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        set_track_access(&db_client, true).await.unwrap();
+        for url in ["http://a.test", "http://b.test"] {
+            put(&db_client, test_record(url, "body")).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // reading a makes b the least recently used, however the method is written
+        get_record(
+            &db_client,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            " get ".to_string(),
+            String::new(),
+            &[],
+            now_millis(),
+        )
+        .await
+        .unwrap();
+        set_max_entries(&db_client, Some(1)).await.unwrap();
+        let get = |url: &str| {
+            get_record(
+                &db_client,
+                DEFAULT_TABLE,
+                url.to_string(),
+                "GET".to_string(),
+                String::new(),
+                &[],
+                now_millis(),
+            )
+        };
+        assert!(get("http://a.test").await.is_some());
+        assert!(get("http://b.test").await.is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_record_serde_round_trip() {
+        let record = Record {
+            etag: Some("\"v1\"".to_string()),
+            ..test_record("http://a.test", "serialized")
+        };
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["expires"], record.expires);
+        assert_eq!(json["cached"], false);
+        assert!(json["changed"].is_null());
+        let back: Record = serde_json::from_value(json).unwrap();
+        assert_eq!(back, record);
+    }
+
+    #[tokio::test]
+    async fn test_lookups_use_index() {
+        let clean = TestCleanup {
+            path: "test_lookups_use_index".to_string(),
+        };
+        // reopening runs the setup again, which must not fail on the existing index
+        create_connection(clean.path.clone()).await.unwrap();
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        let query = "EXPLAIN QUERY PLAN SELECT * FROM requests WHERE request = 'a' AND method = 'GET' AND body = '' AND expires > 0;";
+        let plan = db_client
+            .conn(move |conn| {
+                let mut stmt = conn.prepare(query)?;
+                let rows = stmt.query_map([], |row| row.get::<_, String>(3))?;
+                rows.collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .unwrap();
+        assert!(plan.iter().any(|step| step.contains("idx_requests_lookup")));
+    }
+
+    #[tokio::test]
+    async fn test_long_urls_are_keyed_by_hash() {
+        let db_client = create_memory_connection().await.unwrap();
+        let url = format!("http://a.test/?signature={}", "x".repeat(10_000));
+        put(&db_client, test_record(&url, "long")).await.unwrap();
+        put(&db_client, test_record("http://a.test/", "short"))
+            .await
+            .unwrap();
+        let record = get_cached(&db_client, url.clone(), "GET".to_string())
+            .await
+            .unwrap();
+        assert_eq!(record.response, "long");
+        let hashes = db_client
+            .conn(|conn| {
+                let mut stmt = conn.prepare("SELECT key_hash FROM requests;")?;
+                let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                rows.collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert!(hashes.iter().all(|hash| hash.len() == 64));
+        assert_ne!(hashes[0], hashes[1]);
+    }
+
+    #[tokio::test]
+    async fn test_wal_is_configurable() {
+        let clean = TestCleanup {
+            path: "test_wal".to_string(),
+        };
+        let journal_mode = |db_client: Client| async move {
+            db_client
+                .conn(|conn| {
+                    conn.query_row("PRAGMA journal_mode;", [], |row| row.get::<_, String>(0))
+                })
+                .await
+                .unwrap()
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        assert_eq!(journal_mode(db_client).await, "wal");
+        // reopening a WAL database is fine
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        assert_eq!(journal_mode(db_client).await, "wal");
+        let rollback = TestCleanup {
+            path: "test_rollback".to_string(),
+        };
+        let cache = RequestCache::builder()
+            .db_path(rollback.path.clone())
+            .wal(false)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(journal_mode(cache.connection().clone()).await, "delete");
+    }
+
+    #[tokio::test]
+    async fn test_memory_connection() {
+        let db_client = create_memory_connection().await.unwrap();
+        put(&db_client, test_record("http://a.test", "in memory"))
+            .await
+            .unwrap();
+        // later calls on the same Client see the record, and a clone shares the database
+        let shared = db_client.clone();
+        let record = get_record(
+            &shared,
+            DEFAULT_TABLE,
+            "http://a.test".to_string(),
+            "GET".to_string(),
+            String::new(),
+            &[],
+            now_millis(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(record.response, "in memory");
+        // a second in-memory connection is a separate database
+        let other = create_memory_connection().await.unwrap();
+        assert_eq!(count_rows(&other).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_cached_never_fetches() {
+        let db_client = create_memory_connection().await.unwrap();
+        let url = mock_server(|_| panic!("get_cached made a request")).await;
+        assert!(get_cached(&db_client, url.clone(), "GET".to_string())
+            .await
+            .is_none());
+        put(&db_client, test_record(&url, "cached body"))
+            .await
+            .unwrap();
+        let record = get_cached(&db_client, url, "get".to_string())
+            .await
+            .unwrap();
+        assert_eq!(record.response, "cached body");
+        assert!(record.cached && record.was_cached());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_a_fetch() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", "shared")
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let (a, b, c) = tokio::join!(cache.get(&url), cache.get(&url), cache.get(&url));
+        for resp in [a, b, c] {
+            assert_eq!(resp.unwrap().response, "shared");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // a failed fetch reaches everyone that waited on it
+        let refused = "http://127.0.0.1:1".to_string();
+        let (a, b) = tokio::join!(cache.get(&refused), cache.get(&refused));
+        assert!(matches!(a.unwrap_err(), CacheError::Shared(_)));
+        assert!(matches!(b.unwrap_err(), CacheError::Shared(_)));
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetch_is_shared_with_or_without_followers() {
+        let url = mock_server(|_| {
+            std::thread::sleep(Duration::from_millis(300));
+            http_response("200 OK", "too slow")
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .request_timeout(Duration::from_millis(150))
+            .build()
+            .await
+            .unwrap();
+        let timed_out = |err: CacheError| matches!(err, CacheError::Shared(err) if matches!(*err, CacheError::Timeout(_)));
+        // the follower joins while the leader is still waiting on the server
+        let follower = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cache.get(&url).await
+        };
+        let (leader, follower) = tokio::join!(cache.get(&url), follower);
+        assert!(timed_out(leader.unwrap_err()));
+        assert!(timed_out(follower.unwrap_err()));
+        assert_eq!(cache.stats().shared, 1);
+        // a leader nothing joined gets the same error
+        assert!(timed_out(cache.get(&url).await.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_with_different_headers_fetch_separately() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let accept = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("accept: "))
+                .unwrap_or_default();
+            format!(
+                "HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{accept}",
+                accept.len()
+            )
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let accept = |value: &str| vec![("Accept".to_string(), value.to_string())];
+        let (json, text) = tokio::join!(
+            cache.request_with_headers("GET", &url, accept("application/json")),
+            cache.request_with_headers("GET", &url, accept("text/plain")),
+        );
+        assert_eq!(json.unwrap().response, "application/json");
+        assert_eq!(text.unwrap().response, "text/plain");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().shared, 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalidation_during_fetch() {
+        // the first request is held until released, later ones are answered straight away
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let received = Arc::new(tokio::sync::Notify::new());
+        let release = Arc::new(tokio::sync::Notify::new());
+        let (receiving, releasing) = (received.clone(), release.clone());
+        tokio::spawn(async move {
+            for body in ["before", "after"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (receiving, releasing) = (receiving.clone(), releasing.clone());
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0; 4096];
+                    while !String::from_utf8_lossy(&buf).contains("\r\n\r\n") {
+                        let n = socket.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    if body == "before" {
+                        receiving.notify_one();
+                        releasing.notified().await;
+                    }
+                    let response = http_response("200 OK", body);
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let (leader, later) = tokio::join!(cache.get(&url), async {
+            received.notified().await;
+            cache.invalidate_url(&url).await.unwrap();
+            // a request after the invalidation doesn't join the one in flight, which would
+            // wait on it forever
+            let later = tokio::time::timeout(Duration::from_secs(5), cache.get(&url));
+            let later = later.await.expect("joined the invalidated fetch").unwrap();
+            release.notify_one();
+            later
+        });
+        // the fetch in flight still answers its caller, but doesn't replace what came after
+        assert_eq!(leader.unwrap().response, "before");
+        assert_eq!(later.response, "after");
+        let resp = cache.get(&url).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.response, "after");
+        assert_eq!(cache.stats().shared, 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_cache_stats() {
+        let url = mock_server(|_| http_response("200 OK", "counted")).await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        cache.get(&url).await.unwrap();
+        cache.get(&url).await.unwrap();
+        let stats = cache.stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 1);
+        cache.refresh("GET", &url).await.unwrap();
+        assert!(cache.get("http://127.0.0.1:1").await.is_err());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                refreshes: 1,
+                shared: 0,
+                errors: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_table_name_separates_caches() {
+        let clean = TestCleanup {
+            path: "test_table_name".to_string(),
+        };
+        let url = mock_server(|raw| {
+            let path = raw.split_whitespace().nth(1).unwrap_or_default();
+            http_response("200 OK", path)
+        })
+        .await;
+        let external = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .table_name("external_api")
+            .build()
+            .await
+            .unwrap();
+        let internal = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .build()
+            .await
+            .unwrap();
+        external.get(&format!("{url}/a")).await.unwrap();
+        // the same request through the other table is a miss
+        let resp = internal.get(&format!("{url}/a")).await.unwrap();
+        assert!(!resp.cached);
+        assert!(external.get(&format!("{url}/a")).await.unwrap().cached);
+        assert_eq!(count_rows(internal.connection()).await, 1);
+        for bad in [
+            "",
+            "1st",
+            "drop table;",
+            "settings",
+            "ranges",
+            "sqlite_master",
+        ] {
+            let err = RequestCache::builder()
+                .in_memory()
+                .table_name(bad)
+                .build()
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(err, CacheError::InvalidTableName(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_uses_the_cache_table() {
+        let clean = TestCleanup {
+            path: "test_maintenance_table".to_string(),
+        };
+        let cache = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .table_name("external_api")
+            .build()
+            .await
+            .unwrap();
+        let db_client = cache.connection();
+        put(
+            db_client,
+            test_record("http://a.test/kept", "default table"),
+        )
+        .await
+        .unwrap();
+        for path in ["one", "two", "three"] {
+            let record = test_record(&format!("http://b.test/{path}"), path);
+            cache.put(record).await.unwrap();
+        }
+        assert_eq!(cache.len().await.unwrap(), 3);
+        assert_eq!(cache.breakdown_by_host().await.unwrap()["b.test"], 3);
+        assert_eq!(cache.breakdown_by_method().await.unwrap()["GET"], 3);
+        assert_eq!(cache.verify_all(false).await.unwrap().checked, 3);
+        assert!(cache
+            .touch("GET", "http://b.test/one", Duration::from_secs(60))
+            .await
+            .unwrap());
+        assert_eq!(
+            cache.invalidate("GET", "http://b.test/one").await.unwrap(),
+            1
+        );
+        assert_eq!(cache.invalidate_url("http://b.test/two").await.unwrap(), 1);
+        let removed = cache.invalidate_where_body(|body| body == "three");
+        assert_eq!(removed.await.unwrap(), 1);
+        assert!(cache.is_empty().await.unwrap());
+        cache
+            .put(test_record("http://b.test/four", "four"))
+            .await
+            .unwrap();
+        assert_eq!(cache.invalidate_prefix("http://b.test/").await.unwrap(), 1);
+        cache
+            .put(test_record("http://b.test/five", "five"))
+            .await
+            .unwrap();
+        cache.invalidate_before(now_millis() + 1000).await.unwrap();
+        let resp = cache.request_with_mode("GET", "http://b.test/five", CacheMode::OnlyIfFresh);
+        assert!(matches!(resp.await, Err(CacheError::NotCached)));
+        assert_eq!(cache.clear().await.unwrap(), 1);
+        // the default table is left alone throughout
+        let kept = get_cached(
+            db_client,
+            "http://a.test/kept".to_string(),
+            "GET".to_string(),
+        );
+        assert!(kept.await.is_some());
+        assert_eq!(cache_len(db_client).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_policy_none_caches_redirect() {
+        let url = mock_server(|raw| {
+            if raw.starts_with("GET /moved") {
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: /target\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            } else if raw.starts_with("GET /found") {
+                "HTTP/1.1 302 Found\r\nLocation: /target\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            } else {
+                http_response("200 OK", "target")
+            }
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .redirect_policy(RedirectPolicy::None)
+            .build()
+            .await
+            .unwrap();
+        for cached in [false, true] {
+            let resp = cache.get(&format!("{url}/moved")).await.unwrap();
+            assert_eq!(resp.cached, cached);
+            assert_eq!(resp.status, 301);
+            assert_eq!(resp.location.as_deref(), Some("/target"));
+            assert_eq!(resp.final_url, resp.request);
+        }
+        // a temporary redirect isn't cacheable by default
+        for _ in 0..2 {
+            let resp = cache.get(&format!("{url}/found")).await.unwrap();
+            assert!(resp.status == 302 && !resp.cached);
+        }
+        let following = RequestCache::builder().in_memory().build().await.unwrap();
+        for cached in [false, true] {
+            let resp = following.get(&format!("{url}/moved")).await.unwrap();
+            assert_eq!(resp.cached, cached);
+            assert_eq!((resp.status, resp.response.as_str()), (200, "target"));
+            assert_eq!(resp.request, format!("{url}/moved"));
+            assert_eq!(resp.final_url, format!("{url}/target"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_proxy() {
+        // the proxy sees the full target url and the credentials from the proxy url
+        let proxy = mock_server(|raw| {
+            let target = raw.split_whitespace().nth(1).unwrap_or_default();
+            let auth = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("proxy-authorization: "))
+                .unwrap_or("none");
+            http_response("200 OK", &format!("{target} {auth}"))
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .proxy(proxy.replace("http://", "http://user:pass@"))
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get("http://origin.test/page").await.unwrap();
+        assert_eq!(resp.response, "http://origin.test/page Basic dXNlcjpwYXNz");
+        let err = RequestCache::builder()
+            .in_memory()
+            .proxy("http://[not a proxy")
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CacheError::Http(_)));
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_failures() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            let attempt = counted.fetch_add(1, Ordering::SeqCst);
+            if raw.starts_with("GET /missing") {
+                http_response("404 Not Found", "missing")
+            } else if attempt < 2 {
+                http_response("503 Service Unavailable", "busy")
+            } else {
+                http_response("200 OK", "recovered")
+            }
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .retry(RetryPolicy::new(3, Duration::from_millis(1)))
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get(&url).await.unwrap();
+        assert_eq!((resp.status, resp.response.as_str()), (200, "recovered"));
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        // a 404 isn't retried
+        let resp = cache.get(&format!("{url}/missing")).await.unwrap();
+        assert_eq!(resp.status, 404);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_negative_ttl_for_client_errors() {
+        let url = mock_server(|raw| {
+            if raw.starts_with("GET /missing") {
+                http_response("404 Not Found", "missing")
+            } else if raw.starts_with("GET /down") {
+                http_response("500 Internal Server Error", "down")
+            } else {
+                http_response("200 OK", "found")
+            }
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .default_timeout(3600)
+            .negative_ttl(30)
+            .build()
+            .await
+            .unwrap();
+        let found = cache.get(&url).await.unwrap();
+        assert_eq!(found.freshness_lifetime(), Duration::from_secs(3600));
+        let missing = cache.get(&format!("{url}/missing")).await.unwrap();
+        assert_eq!(missing.freshness_lifetime(), Duration::from_secs(30));
+        assert!(cache.get(&format!("{url}/missing")).await.unwrap().cached);
+        // server errors aren't stored
+        let down = cache.get(&format!("{url}/down")).await.unwrap();
+        assert_eq!(down.status, 500);
+        assert!(down.changed.is_none());
+        assert_eq!(count_rows(cache.connection()).await, 2);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking_request() {
+        // no runtime here, so serve from a plain thread
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            use std::io::{Read, Write};
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                let _ = socket.read(&mut [0; 4096]);
+                let _ = socket.write_all(http_response("200 OK", "blocking").as_bytes());
+            }
+        });
+        let db_client = blocking::create_memory_connection().unwrap();
+        for cached in [false, true] {
+            let resp =
+                blocking::request(&db_client, url.clone(), "GET".to_string(), 60, None, None)
+                    .unwrap();
+            assert_eq!(resp.response, "blocking");
+            assert_eq!(resp.cached, cached);
+        }
+        assert!(blocking::get_cached(&db_client, url, "GET".to_string()).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_default_and_host_headers() {
+        let url = mock_server(|raw| {
+            let header = |name: &str| {
+                raw.lines()
+                    .find_map(|line| line.strip_prefix(name))
+                    .unwrap_or("none")
+                    .to_string()
+            };
+            let body = format!("{} {}", header("user-agent: "), header("x-key: "));
+            http_response("200 OK", &body)
+        })
+        .await;
+        let other_host = url.replace("127.0.0.1", "localhost");
+        let cache = RequestCache::builder()
+            .in_memory()
+            .user_agent("global-agent")
+            .default_header("X-Key", "global-key")
+            .host_header("LOCALHOST", "User-Agent", "local-agent")
+            .host_header("127.0.0.1", "X-Key", "ip-key")
+            .build()
+            .await
+            .unwrap();
+        // each host gets its own headers, over the global ones
+        let resp = cache.get(&format!("{url}/a")).await.unwrap();
+        assert_eq!(resp.response, "global-agent ip-key");
+        let resp = cache.get(&format!("{other_host}/a")).await.unwrap();
+        assert_eq!(resp.response, "local-agent global-key");
+        // per-call headers win over both
+        let headers = vec![("user-agent".to_string(), "call-agent".to_string())];
+        let resp = cache
+            .request_with_headers("GET", &format!("{other_host}/b"), headers)
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "call-agent global-key");
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_tracing_events() {
+        use tracing::{field::Field, span, Event, Metadata, Subscriber};
+
+        // collect each event's message
+        #[derive(Clone, Default)]
+        struct Messages(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl tracing::field::Visit for Messages {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0.lock().unwrap().push(format!("{value:?}"));
+                }
+            }
+        }
+
+        impl Subscriber for Messages {
+            fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+                // only this crate's events, not reqwest's
+                metadata.target().starts_with("request_cache")
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                event.record(&mut self.clone());
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let messages = Messages::default();
+        let _guard = tracing::subscriber::set_default(messages.clone());
+        let url = mock_server(|_| http_response("200 OK", "traced")).await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        cache.get(&url).await.unwrap();
+        cache.get(&url).await.unwrap();
+        assert_eq!(
+            *messages.0.lock().unwrap(),
+            ["response received", "cache miss", "cache hit"]
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn test_otel_spans() {
+        use opentelemetry::{global::BoxedTracer, trace::TracerProvider, KeyValue};
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let url = mock_server(|_| http_response("200 OK", "traced")).await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .otel_tracer(BoxedTracer::new(Box::new(provider.tracer("test"))))
+            .build()
+            .await
+            .unwrap();
+        cache.get(&url).await.unwrap();
+        cache.get(&url).await.unwrap();
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 2);
+        for (span, hit, status) in [(&spans[0], false, "Miss"), (&spans[1], true, "Hit")] {
+            assert_eq!(span.name, "GET");
+            assert_eq!(span.span_kind, opentelemetry::trace::SpanKind::Client);
+            for attribute in [
+                KeyValue::new("http.request.method", "GET"),
+                KeyValue::new("url.full", url.clone()),
+                KeyValue::new("http.response.status_code", 200),
+                KeyValue::new("cache.hit", hit),
+                KeyValue::new("cache.status", status),
+            ] {
+                assert!(span.attributes.contains(&attribute), "{attribute:?}");
+            }
+        }
+        // a failed request's span is marked as an error
+        let _ = cache.get("http://127.0.0.1:1/").await;
+        let spans = exporter.get_finished_spans().unwrap();
+        assert!(matches!(
+            spans[2].status,
+            opentelemetry::trace::Status::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cache_modes() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let get = |mode| cache.request_with_mode("GET", &url, mode);
+        assert!(matches!(
+            get(CacheMode::OnlyIfCached).await,
+            Err(CacheError::NotCached)
+        ));
+        // NoStore fetches without writing
+        assert_eq!(get(CacheMode::NoStore).await.unwrap().response, "fetch 0");
+        assert_eq!(count_rows(cache.connection()).await, 0);
+        assert_eq!(get(CacheMode::Default).await.unwrap().response, "fetch 1");
+        // NoCache goes to the server even with an unexpired record
+        assert_eq!(get(CacheMode::NoCache).await.unwrap().response, "fetch 2");
+        // expired records are still served by ForceCache and OnlyIfCached
+        let expire = "UPDATE requests SET expires = 1;";
+        cache
+            .connection()
+            .conn(move |conn| conn.execute(expire, []))
+            .await
+            .unwrap();
+        for mode in [CacheMode::ForceCache, CacheMode::OnlyIfCached] {
+            let resp = get(mode).await.unwrap();
+            assert_eq!(resp.response, "fetch 2");
+            assert!(resp.cached);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_expires_records() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
+        .await;
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let cache = RequestCache::builder()
+            .in_memory()
+            .default_timeout(60)
+            .clock(clock.clone())
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get(&url).await.unwrap();
+        assert_eq!(resp.fetched_at, 1_000_000);
+        assert_eq!(resp.expires, 1_060_000);
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(cache.get(&url).await.unwrap().response, "fetch 0");
+        // past the timeout the record is refetched, with no sleeping
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cache.get(&url).await.unwrap().response, "fetch 1");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sub_second_ttl() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
+        .await;
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let cache = RequestCache::builder()
+            .in_memory()
+            .default_ttl(Duration::from_millis(250))
+            .clock(clock.clone())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(cache.get(&url).await.unwrap().expires, 1_000_250);
+        clock.advance(Duration::from_millis(249));
+        assert_eq!(cache.get(&url).await.unwrap().response, "fetch 0");
+        // a record expiring this millisecond isn't served
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(cache.get(&url).await.unwrap().response, "fetch 1");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
+        .await;
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let cache = RequestCache::builder()
+            .in_memory()
+            .default_timeout(60)
+            .clock(clock.clone())
+            .build()
+            .await
+            .unwrap();
+        let swr = || cache.request_with_mode("GET", &url, CacheMode::StaleWhileRevalidate);
+        // a miss fetches as usual, then an unexpired record is a plain hit
+        assert!(!swr().await.unwrap().stale);
+        assert!(!swr().await.unwrap().stale);
+        clock.advance(Duration::from_secs(61));
+        let resp = swr().await.unwrap();
+        assert_eq!(resp.response, "fetch 0");
+        assert!(resp.stale && resp.cached);
+        // the refresh runs in the background, so wait for it to store the new record
+        for _ in 0..100 {
+            let fresh = cache.request_with_mode("GET", &url, CacheMode::OnlyIfCached);
+            if !fresh.await.unwrap().stale {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let resp = cache.get(&url).await.unwrap();
+        assert_eq!(resp.response, "fetch 1");
+        assert!(!resp.stale);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        let big = "x".repeat(1 << 20);
+        let url = mock_server(move |raw| {
+            if raw.starts_with("GET /small") {
+                http_response("200 OK", "small")
+            } else if raw.starts_with("GET /unsized") {
+                // no Content-Length, so the limit is only found while reading
+                format!("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{big}")
+            } else {
+                http_response("200 OK", &big)
+            }
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .max_response_bytes(1024)
+            .build()
+            .await
+            .unwrap();
+        for path in ["sized", "unsized"] {
+            let err = cache.get(&format!("{url}/{path}")).await.unwrap_err();
+            let CacheError::Shared(err) = err else {
+                panic!("{err:?}");
+            };
+            assert!(matches!(*err, CacheError::TooLarge(1024)), "{err:?}");
+        }
+        assert_eq!(count_rows(cache.connection()).await, 0);
+        let resp = cache.get(&format!("{url}/small")).await.unwrap();
+        assert_eq!(resp.response, "small");
+        assert_eq!(count_rows(cache.connection()).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_bodies() {
+        let body = "compressible ".repeat(10_000);
+        let served = body.clone();
+        let url = mock_server(move |_| http_response("200 OK", &served)).await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .compress(true)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(cache.get(&url).await.unwrap().response, body);
+        let query = "SELECT length(response_bytes), response IS NULL, compressed FROM requests;";
+        let (stored, no_text, compressed): (usize, bool, bool) = cache
+            .connection()
+            .conn(move |conn| {
+                conn.query_row(query, [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            })
+            .await
+            .unwrap();
+        assert!(stored < body.len() / 10, "{stored} bytes stored");
+        assert!(no_text && compressed);
+        let resp = cache.get(&url).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.response, body);
+        assert_eq!(resp.response_bytes, body.as_bytes());
+        assert!(verify_all(cache.connection(), false)
+            .await
+            .unwrap()
+            .corrupt
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compression_level() {
+        // words in a scrambled order, which higher levels find more of the repetition in
+        let words = [
+            "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel",
+        ];
+        let mut state = 12345u32;
+        let body: String = (0..20_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                words[(state >> 16) as usize % words.len()]
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let served = body.clone();
+        let url = mock_server(move |_| http_response("200 OK", &served)).await;
+        let mut stored = Vec::new();
+        for level in [1, 19] {
+            let cache = RequestCache::builder()
+                .in_memory()
+                .compression_level(level)
+                .build()
+                .await
+                .unwrap();
+            cache.get(&url).await.unwrap();
+            let query = "SELECT length(response_bytes) FROM requests;";
+            let size: usize = cache
+                .connection()
+                .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+                .await
+                .unwrap();
+            assert_eq!(cache.get(&url).await.unwrap().response, body);
+            stored.push(size);
+        }
+        assert!(stored[1] < stored[0], "{stored:?} bytes stored");
+        for bad in [23, i32::MAX] {
+            let err = RequestCache::builder()
+                .in_memory()
+                .compression_level(bad)
+                .build()
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(err, CacheError::InvalidCompressionLevel(level) if level == bad));
+        }
+        let db_client = create_memory_connection().await.unwrap();
+        let err = set_compression_level(&db_client, 100).await.unwrap_err();
+        assert!(matches!(err, CacheError::InvalidCompressionLevel(100)));
+    }
+
+    #[tokio::test]
+    async fn test_deduplicated_bodies() {
+        let body = "the same page ".repeat(1_000);
+        let served = body.clone();
+        let url = mock_server(move |_| http_response("200 OK", &served)).await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .compress(true)
+            .dedupe_bodies(true)
+            .build()
+            .await
+            .unwrap();
+        cache
+            .request_tagged("GET", &format!("{url}/a"), "a")
+            .await
+            .unwrap();
+        cache.get(&format!("{url}/b")).await.unwrap();
+        let blobs = || {
+            let query = "SELECT COUNT(*), COALESCE(SUM(refs), 0) FROM blobs;";
+            let counts = |row: &Row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?));
+            cache
+                .connection()
+                .conn(move |conn| conn.query_row(query, [], counts))
+        };
+        // one blob for both records, which keep no body of their own
+        assert_eq!(blobs().await.unwrap(), (1, 2));
+        let query = "SELECT COUNT(*) FROM requests WHERE response_bytes IS NULL;";
+        let referencing: i64 = cache
+            .connection()
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(referencing, 2);
+        for path in ["a", "b"] {
+            let resp = cache.get(&format!("{url}/{path}")).await.unwrap();
+            assert!(resp.cached);
+            assert_eq!(resp.response, body);
+            let (_, stream) = cache.get_stream(&format!("{url}/{path}")).await.unwrap();
+            assert_eq!(stream.bytes().await.unwrap(), body.as_bytes());
+        }
+        assert!(verify_all(cache.connection(), false)
+            .await
+            .unwrap()
+            .corrupt
+            .is_empty());
+        // the blob goes with the last record referencing it
+        cache
+            .purge_where(PurgeCriteria::new().tag("a"))
+            .await
+            .unwrap();
+        assert_eq!(blobs().await.unwrap(), (1, 1));
+        assert_eq!(cache.get(&format!("{url}/b")).await.unwrap().response, body);
+        purge_expired_from(cache.connection(), DEFAULT_TABLE, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(blobs().await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_body_is_a_miss() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", "intact")
+        })
+        .await;
+        let corrupt = |cache: RequestCache| async move {
+            let query = "UPDATE requests SET response_bytes = CAST('garbage' AS BLOB);";
+            cache
+                .connection()
+                .conn(move |conn| conn.execute(query, []))
+                .await
+                .unwrap();
+            cache
+        };
+        // without verification the corrupted body is served
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        cache.get(&url).await.unwrap();
+        let cache = corrupt(cache).await;
+        assert_eq!(cache.get(&url).await.unwrap().response, "garbage");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // with it the body is fetched again
+        let cache = RequestCache::builder()
+            .in_memory()
+            .verify_bodies(true)
+            .build()
+            .await
+            .unwrap();
+        cache.get(&url).await.unwrap();
+        let cache = corrupt(cache).await;
+        let resp = cache.get(&url).await.unwrap();
+        assert!(!resp.cached);
+        assert_eq!(resp.response, "intact");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        let (resp, stream) = cache.get_stream(&url).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(stream.bytes().await.unwrap(), b"intact");
+        // the corrupted record is deleted rather than served as a fallback
+        let cache = corrupt(cache).await;
+        let only_cached = cache.request_with_mode("GET", &url, CacheMode::OnlyIfCached);
+        assert!(matches!(only_cached.await, Err(CacheError::NotCached)));
+        assert_eq!(count_rows(cache.connection()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_auth_helpers() {
+        assert_eq!(
+            basic_auth("user", "pass"),
+            (
+                "authorization".to_string(),
+                "Basic dXNlcjpwYXNz".to_string()
+            )
+        );
+        // respond with the Authorization header the request was sent with
+        let url = mock_server(|raw| {
+            let auth = raw.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("authorization")
+                    .then(|| value.trim().to_string())
+            });
+            http_response("200 OK", &auth.unwrap_or_default())
+        })
+        .await;
+        let auth = |token| vec![bearer_auth(token)];
+        // by default whoever fetched the record first is served to everyone
+        let shared = RequestCache::builder()
+            .in_memory()
+            .bearer_auth("first")
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(shared.get(&url).await.unwrap().response, "Bearer first");
+        let resp = shared.request_with_headers("GET", &url, auth("second"));
+        let resp = resp.await.unwrap();
+        assert_eq!(resp.response, "Bearer first");
+        assert!(resp.cached);
+        let per_user = RequestCache::builder()
+            .in_memory()
+            .auth_in_cache_key(true)
+            .build()
+            .await
+            .unwrap();
+        for token in ["first", "second", "first"] {
+            let resp = per_user.request_with_headers("GET", &url, auth(token));
+            let resp = resp.await.unwrap();
+            assert_eq!(resp.response, format!("Bearer {token}"));
+        }
+        assert_eq!(count_rows(per_user.connection()).await, 2);
+        // the credentials are keyed on by digest, not stored
+        let query = "SELECT COUNT(*) FROM requests WHERE body LIKE '%Bearer%';";
+        let stored: i64 = per_user
+            .connection()
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(stored, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cookie_store() {
+        // log in to get a session cookie, then echo the cookies each request is sent with
+        let url = mock_server(|raw| {
+            if raw.starts_with("GET /login") {
+                return "HTTP/1.1 200 OK\r\nSet-Cookie: session=abc; Path=/\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string();
+            }
+            let cookies = raw.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("cookie")
+                    .then(|| value.trim().to_string())
+            });
+            http_response("200 OK", &cookies.unwrap_or_default())
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .cookie_store(true)
+            .build()
+            .await
+            .unwrap();
+        cache.get(&format!("{url}/login")).await.unwrap();
+        let resp = cache.get(&format!("{url}/data")).await.unwrap();
+        assert_eq!(resp.response, "session=abc");
+        // a jar can be seeded before the cache is built
+        let jar = Arc::new(reqwest::cookie::Jar::default());
+        jar.add_cookie_str("seeded=1", &url.parse().unwrap());
+        let seeded = RequestCache::builder()
+            .in_memory()
+            .cookie_jar(jar)
+            .build()
+            .await
+            .unwrap();
+        let resp = seeded.get(&format!("{url}/data")).await.unwrap();
+        assert_eq!(resp.response, "seeded=1");
+        assert!(seeded.cookie_jar().is_some());
+        let plain = RequestCache::builder().in_memory().build().await.unwrap();
+        assert!(plain.cookie_jar().is_none());
+        assert_eq!(
+            plain.get(&format!("{url}/data")).await.unwrap().response,
+            ""
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_json_helpers() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Item {
+            id: u32,
+            name: String,
+        }
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+            let head = head.to_ascii_lowercase();
+            if raw.starts_with("POST /echo") && head.contains("content-type: application/json") {
+                http_response("200 OK", body)
+            } else if raw.starts_with("GET /item") && head.contains("accept: application/json") {
+                http_response("200 OK", r#"{"id": 1, "name": "one"}"#)
+            } else {
+                http_response("200 OK", "not json")
+            }
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let expected = Item {
+            id: 1,
+            name: "one".to_string(),
+        };
+        for _ in 0..2 {
+            let item: Item = cache.get_json(&format!("{url}/item")).await.unwrap();
+            assert_eq!(item, expected);
+        }
+        // the raw response is what's cached
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let raw = cache.get(&format!("{url}/item")).await.unwrap();
+        assert_eq!(raw.json::<Item>().unwrap(), expected);
+        let sent = serde_json::json!({"query": [1, 2]});
+        let echoed: serde_json::Value = cache
+            .post_json(&format!("{url}/echo"), &sent)
+            .await
+            .unwrap();
+        assert_eq!(echoed, sent);
+        let err = cache
+            .get_json::<Item>(&format!("{url}/text"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CacheError::Deserialize(_, body) if body == "not json"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let url = mock_server(|_| http_response("200 OK", "limited")).await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .rate_limit("*.example.test", 1)
+            .rate_limit("127.0.0.1", 10)
+            .build()
+            .await
+            .unwrap();
+        let urls: Vec<_> = (0..15).map(|i| format!("{url}/{i}")).collect();
+        // a second's worth go at once, then the other five wait a tenth of a second each
+        let start = std::time::Instant::now();
+        for url in &urls {
+            assert!(!cache.get(url).await.unwrap().cached);
+        }
+        assert!(
+            start.elapsed() >= Duration::from_millis(450),
+            "{:?}",
+            start.elapsed()
+        );
+        // cache hits aren't throttled
+        let start = std::time::Instant::now();
+        for url in &urls {
+            assert!(cache.get(url).await.unwrap().cached);
+        }
+        assert!(
+            start.elapsed() < Duration::from_millis(400),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_head_requests_cache_status_and_headers() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &"x".repeat(10_000))
+        })
+        .await;
+        // the Content-Length of a HEAD response isn't a body to hold to the limit
+        let cache = RequestCache::builder()
+            .in_memory()
+            .max_response_bytes(1024)
+            .build()
+            .await
+            .unwrap();
+        for cached in [false, true] {
+            let resp = cache.request("HEAD", &url).await.unwrap();
+            assert_eq!(resp.cached, cached);
+            assert_eq!(resp.method, "HEAD");
+            assert_eq!(resp.status, 200);
+            assert!(resp.response.is_empty() && resp.response_bytes.is_empty());
+            assert_eq!(resp.header("content-length"), Some("10000"));
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // a GET is kept apart from the HEAD
+        let err = cache.get(&url).await.unwrap_err();
+        assert!(
+            matches!(err, CacheError::Shared(err) if matches!(*err, CacheError::TooLarge(1024)))
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_warm() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let path = raw.split(' ').nth(1).unwrap_or_default();
+            http_response("200 OK", path)
+        })
+        .await;
+        let db_client = create_memory_connection().await.unwrap();
+        let get = |path: &str| (format!("{url}{path}"), "GET".to_string());
+        let (fresh, _) = get("/fresh");
+        request(&db_client, fresh, "GET".to_string(), 60, None, None)
+            .await
+            .unwrap();
+        let mut urls: Vec<_> = (0..8).map(|i| get(&format!("/{i}"))).collect();
+        urls.push(get("/fresh"));
+        urls.push(("http://127.0.0.1:1/".to_string(), "GET".to_string()));
+        urls.push((format!("{url}/bad"), "NOT A METHOD".to_string()));
+        let report = warm(&db_client, urls, 60, None, 3).await;
+        assert_eq!(report.fetched.len(), 8);
+        assert_eq!(report.skipped, vec![get("/fresh")]);
+        assert_eq!(report.failed.len(), 2);
+        assert!(report
+            .failed
+            .iter()
+            .any(|(_, _, err)| matches!(err, CacheError::InvalidMethod(_))));
+        assert_eq!(hits.load(Ordering::SeqCst), 9);
+        // the job itself is then served from the cache
+        for i in 0..8 {
+            let (url, method) = get(&format!("/{i}"));
+            let record = db_client.get_record(&url, &method, "", &[]).await.unwrap();
+            assert_eq!(record.response, format!("/{i}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_root_certificate() {
+        let clean = TestCleanup {
+            path: "test_invalid_root_certificate.pem".to_string(),
+        };
+        fs::write(&clean.path, "not a certificate").unwrap();
+        for path in ["test_missing_certificate.pem", clean.path.as_str()] {
+            let err = RequestCache::builder()
+                .in_memory()
+                .add_root_certificate(path)
+                .build()
+                .await
+                .err()
+                .unwrap();
+            assert!(
+                matches!(&err, CacheError::InvalidCertificate(invalid, _) if invalid.to_str() == Some(path)),
+                "{err:?}"
+            );
+        }
+        // without certificates to check, accepting invalid ones still builds
+        let cache = RequestCache::builder()
+            .in_memory()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .await;
+        assert!(cache.is_ok());
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        let dropped = ["utm_*".to_string(), "fbclid".to_string()];
+        let cases = [
+            ("http://example.com/?a=1&b=2", "http://example.com/?a=1&b=2"),
+            ("http://example.com/?b=2&a=1", "http://example.com/?a=1&b=2"),
+            (
+                "HTTP://Example.COM:80/path?x=1",
+                "http://example.com/path?x=1",
+            ),
+            ("https://example.com:443/", "https://example.com/"),
+            ("http://example.com:8080/", "http://example.com:8080/"),
+            (
+                "http://example.com/?q=2&utm_source=a&q=1&fbclid=b",
+                "http://example.com/?q=2&q=1",
+            ),
+            (
+                "http://example.com/?utm_medium=email",
+                "http://example.com/",
+            ),
+            ("not a url", "not a url"),
+        ];
+        for (url, normalized) in cases {
+            assert_eq!(normalize_url(url, &dropped), normalized, "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_normalized_urls_share_a_record() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
+        .await;
+        let spellings = [
+            format!("{url}/?a=1&b=2"),
+            format!("{url}/?b=2&a=1"),
+            format!("{url}/?utm_source=feed&b=2&a=1"),
+        ];
+        let normalizing = RequestCache::builder()
+            .in_memory()
+            .normalize_urls(true)
+            .drop_query_param("utm_*")
+            .build()
+            .await
+            .unwrap();
+        for spelling in &spellings {
+            assert_eq!(normalizing.get(spelling).await.unwrap().response, "fetch 0");
+        }
+        assert_eq!(count_rows(normalizing.connection()).await, 1);
+        // without normalization each spelling is its own record
+        let plain = RequestCache::builder().in_memory().build().await.unwrap();
+        for spelling in &spellings {
+            assert!(!plain.get(spelling).await.unwrap().cached);
+        }
+        assert_eq!(count_rows(plain.connection()).await, 3);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_custom_cache_key() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", raw.split(' ').nth(1).unwrap_or_default())
+        })
+        .await;
+        // the timestamp param doesn't change the response, so leave it out of the key
+        let cache = RequestCache::builder()
+            .in_memory()
+            .cache_key(|method, url| {
+                let key = url.split_once("?ts=").map_or(url, |(key, _)| key);
+                format!("{method} {key}")
+            })
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get(&format!("{url}/data?ts=1")).await.unwrap();
+        // the url with the timestamp is what was fetched
+        assert_eq!(resp.response, "/data?ts=1");
+        let resp = cache.get(&format!("{url}/data?ts=2")).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.response, "/data?ts=1");
+        assert_eq!(resp.request, format!("{url}/data?ts=2"));
+        assert!(
+            !cache
+                .get(&format!("{url}/other?ts=3"))
+                .await
+                .unwrap()
+                .cached
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_canonical_json_bodies_share_a_record() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", "ok")
+        })
+        .await;
+        let db_client = create_memory_connection().await.unwrap();
+        let store = SqliteStore::new(db_client.clone()).canonical_json(true);
+        let post = |body: &str| {
+            let options = RequestOptions {
+                cache_unsafe: true,
+                body: Some(body.to_string()),
+                ..Default::default()
+            };
+            request_with_options(&store, url.clone(), "POST".to_string(), 60, options)
+        };
+        assert!(
+            !post(r#"{"a": 1, "b": [1, {"d": 2, "c": 3}]}"#)
+                .await
+                .unwrap()
+                .cached
+        );
+        let resp = post(r#"{"b":[1,{"c":3,"d":2}],"a":1}"#).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(
+            resp.body.as_deref(),
+            Some(r#"{"b":[1,{"c":3,"d":2}],"a":1}"#)
+        );
+        // the order within arrays matters, and bodies that aren't JSON are keyed as sent
+        assert!(
+            !post(r#"{"a": 1, "b": [{"d": 2, "c": 3}, 1]}"#)
+                .await
+                .unwrap()
+                .cached
+        );
+        assert!(!post("{not json").await.unwrap().cached);
+        assert!(!post("{not  json").await.unwrap().cached);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        assert_eq!(count_rows(&db_client).await, 4);
+        // without the option the same bodies are keyed as sent
+        let store = SqliteStore::new(db_client.clone());
+        let options = RequestOptions {
+            cache_unsafe: true,
+            body: Some(r#"{"b":[1,{"c":3,"d":2}],"a":1}"#.to_string()),
+            ..Default::default()
+        };
+        let resp = request_with_options(&store, url.clone(), "POST".to_string(), 60, options);
+        assert!(!resp.await.unwrap().cached);
+    }
+
+    #[tokio::test]
+    async fn test_alias_urls_share_a_record() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", raw.split(' ').nth(1).unwrap_or_default())
+        })
+        .await;
+        let canonical = format!("{url}/primary/");
+        let mirror = format!("{url}/mirror/");
+        let resolver = (canonical.clone(), mirror.clone());
+        let cache = RequestCache::builder()
+            .in_memory()
+            .alias_resolver(move |url| url.replacen(&resolver.1, &resolver.0, 1))
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get(&format!("{mirror}data")).await.unwrap();
+        assert!(!resp.cached);
+        assert_eq!(resp.response, "/mirror/data");
+        let resp = cache.get(&format!("{canonical}data")).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.response, "/mirror/data");
+        assert_eq!(resp.request, format!("{canonical}data"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(count_rows(cache.connection()).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_significant_query_params() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", raw.split(' ').nth(1).unwrap_or_default())
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .significant_query_params(["page", "filter_*"])
+            .build()
+            .await
+            .unwrap();
+        let resp = cache
+            .get(&format!("{url}/items?page=1&ref=a"))
+            .await
+            .unwrap();
+        // the url fetched keeps every param
+        assert_eq!(resp.response, "/items?page=1&ref=a");
+        let resp = cache
+            .get(&format!("{url}/items?ref=b&page=1"))
+            .await
+            .unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.request, format!("{url}/items?ref=b&page=1"));
+        assert!(
+            !cache
+                .get(&format!("{url}/items?page=2&ref=a"))
+                .await
+                .unwrap()
+                .cached
+        );
+        let filtered = format!("{url}/items?page=1&filter_tag=x");
+        assert!(!cache.get(&filtered).await.unwrap().cached);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(count_rows(cache.connection()).await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_query_params() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", raw.split(' ').nth(1).unwrap_or_default())
+        })
+        .await;
+        assert_eq!(with_query("http://x", &[("a", "1")]), "http://x?a=1");
+        assert_eq!(
+            with_query("http://x/p?a=1#top", &[("b", "two words"), ("c", "&")]),
+            "http://x/p?a=1&b=two+words&c=%26#top"
+        );
+        assert_eq!(with_query("http://x?", &[("a", "1")]), "http://x?a=1");
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let params = vec![("a".to_string(), "1".to_string())];
+        let resp = cache
+            .get_with_query(&format!("{url}/q"), &params)
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "/q?a=1");
+        // the url written out is the same request
+        let resp = cache.get(&format!("{url}/q?a=1")).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(count_rows(cache.connection()).await, 1);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_freshness_from_headers_only() {
+        let clock = Arc::new(MockClock::new(1_000_000_000_000));
+        let date = |millis: i64| {
+            let time = std::time::UNIX_EPOCH + Duration::from_millis(millis as u64);
+            httpdate::fmt_http_date(time)
+        };
+        let (now, hour) = (clock.now_millis(), 3_600_000);
+        let (date_now, date_ago) = (date(now), date(now - 10 * hour));
+        let expires = date(now + hour);
+        let url = mock_server(move |raw| {
+            let path = raw.split(' ').nth(1).unwrap_or_default();
+            let header = match path {
+                "/max-age" => "Cache-Control: max-age=600\r\nAge: 100\r\n".to_string(),
+                "/expires" => format!("Date: {date_now}\r\nExpires: {expires}\r\n"),
+                "/modified" => format!("Date: {date_now}\r\nLast-Modified: {date_ago}\r\n"),
+                "/no-store" => "Cache-Control: no-store\r\n".to_string(),
+                _ => String::new(),
+            };
+            format!("HTTP/1.1 200 OK\r\n{header}Content-Length: 2\r\nConnection: close\r\n\r\nok")
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .default_timeout(60)
+            .freshness(Freshness::HeadersOnly)
+            .clock(clock)
+            .build()
+            .await
+            .unwrap();
+        // max-age less the Age upstream, Expires against Date, and a tenth of the time
+        // since Last-Modified
+        for (path, lifetime) in [("max-age", 500_000), ("expires", hour), ("modified", hour)] {
+            let resp = cache.get(&format!("{url}/{path}")).await.unwrap();
+            assert_eq!(resp.expires - resp.fetched_at, lifetime, "{path}");
+            assert!(resp.changed.is_some());
+        }
+        // nothing says the response can be cached, so the ttl doesn't apply
+        for path in ["plain", "no-store"] {
+            let resp = cache.get(&format!("{url}/{path}")).await.unwrap();
+            assert_eq!(resp.expires, resp.fetched_at);
+            assert!(resp.changed.is_none());
+        }
+        assert_eq!(count_rows(cache.connection()).await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_shared_cache_skips_set_cookie() {
+        let url = mock_server(|raw| {
+            let path = raw.split(' ').nth(1).unwrap_or_default();
+            let cookie = match path {
+                "/cookie" => "Set-Cookie: session=abc; Path=/\r\n",
+                _ => "",
+            };
+            format!("HTTP/1.1 200 OK\r\n{cookie}Content-Length: 2\r\nConnection: close\r\n\r\nok")
+        })
+        .await;
+        for (shared, allowed, stored) in [(false, false, 2), (true, false, 1), (true, true, 2)] {
+            let cache = RequestCache::builder()
+                .in_memory()
+                .shared(shared)
+                .shared_set_cookie(allowed)
+                .build()
+                .await
+                .unwrap();
+            for path in ["cookie", "plain"] {
+                let resp = cache.get(&format!("{url}/{path}")).await.unwrap();
+                assert_eq!(resp.response, "ok");
+            }
+            assert_eq!(
+                count_rows(cache.connection()).await,
+                stored,
+                "{shared} {allowed}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirror_receives_writes() {
+        let url = mock_server(|_| http_response("200 OK", "mirrored")).await;
+        let mirror = create_memory_connection().await.unwrap();
+        let cache = RequestCache::builder()
+            .in_memory()
+            .mirror(mirror.clone())
+            .build()
+            .await
+            .unwrap();
+        // the mirror is written in the background, so wait for it
+        let mirrored = |rows| {
+            let mirror = mirror.clone();
+            let written = async move {
+                while count_rows(&mirror).await != rows {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            async {
+                tokio::time::timeout(Duration::from_secs(5), written)
+                    .await
+                    .unwrap()
+            }
+        };
+        cache.get(&url).await.unwrap();
+        mirrored(1).await;
+        let record = mirror.get_record(&url, "GET", "", &[]).await.unwrap();
+        assert_eq!(record.response, "mirrored");
+        // so are invalidations
+        assert_eq!(cache.invalidate("GET", &url).await.unwrap(), 1);
+        mirrored(0).await;
+        cache.get(&url).await.unwrap();
+        mirrored(1).await;
+        assert_eq!(cache.clear().await.unwrap(), 1);
+        mirrored(0).await;
+    }
+
+    #[tokio::test]
+    async fn test_shared_cache_skips_private_responses() {
+        let url = mock_server(|raw| {
+            let path = raw.split(' ').nth(1).unwrap_or_default();
+            let cache_control = match path {
+                "/private" => "private, max-age=60",
+                "/field" => "private=\"Set-Cookie\"",
+                _ => "public, max-age=60",
+            };
+            format!("HTTP/1.1 200 OK\r\nCache-Control: {cache_control}\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+        })
+        .await;
+        for shared in [false, true] {
+            let cache = RequestCache::builder()
+                .in_memory()
+                .shared(shared)
+                .build()
+                .await
+                .unwrap();
+            for path in ["private", "field", "public"] {
+                let resp = cache.get(&format!("{url}/{path}")).await.unwrap();
+                assert_eq!(resp.response, "ok");
+                let stored = path == "public" || !shared;
+                assert_eq!(resp.changed.is_some(), stored, "{path}, shared {shared}");
+            }
+            let stored = if shared { 1 } else { 3 };
+            assert_eq!(count_rows(cache.connection()).await, stored);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replacing_a_record_never_misses() {
+        let db_client = create_memory_connection().await.unwrap();
+        let url = "http://replace.test";
+        insert_record(&db_client, DEFAULT_TABLE, test_record(url, "0"), "", &[])
+            .await
+            .unwrap();
+        // every insert changes the body, so each one replaces the stored row
+        let writer = async {
+            for n in 1..50 {
+                let record = test_record(url, &n.to_string());
+                insert_record(&db_client, DEFAULT_TABLE, record, "", &[])
+                    .await
+                    .unwrap();
+                tokio::task::yield_now().await;
+            }
+        };
+        let reader = async {
+            for _ in 0..200 {
+                let found = db_client.get_record(url, "GET", "", &[]).await;
+                assert!(found.is_some());
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::join!(writer, reader);
+        assert_eq!(count_rows(&db_client).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_bodies() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        // longer than a chunk read from sqlite
+        let text: String = (0..40_000).map(|n| format!("{n:05}")).collect();
+        let body = text.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &body)
+        })
+        .await;
+        for compress in [false, true] {
+            let cache = RequestCache::builder()
+                .in_memory()
+                .compress(compress)
+                .build()
+                .await
+                .unwrap();
+            let (record, stream) = cache.get_stream(&url).await.unwrap();
+            assert!(!record.cached && record.response_bytes.is_empty());
+            assert_eq!(stream.bytes().await.unwrap(), text.as_bytes());
+            let (record, stream) = cache.get_stream(&url).await.unwrap();
+            assert!(record.cached && record.response_bytes.is_empty());
+            let chunks: Vec<_> = stream.collect().await;
+            assert!(chunks.len() > 1);
+            let streamed: Vec<u8> = chunks
+                .into_iter()
+                .flat_map(|chunk| chunk.unwrap())
+                .collect();
+            assert_eq!(streamed, text.as_bytes());
+            // the whole-body API reads the same record
+            assert_eq!(cache.get(&url).await.unwrap().response, text);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_dropped_early_stores_nothing() {
+        let url = mock_server(|_| http_response("200 OK", &"x".repeat(100_000))).await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let (_, mut stream) = cache.get_stream(&url).await.unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        drop(stream);
+        assert_eq!(count_rows(cache.connection()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_shared_connection_per_path() {
+        let (first, second) = (
+            TestCleanup {
+                path: "test_shared_first".to_string(),
+            },
+            TestCleanup {
+                path: "test_shared_second".to_string(),
+            },
+        );
+        let db_client = shared_connection(first.path.clone()).await.unwrap();
+        put(&db_client, test_record("http://a.test", "a"))
+            .await
+            .unwrap();
+        // the same path gets the open connection back
+        let again = shared_connection(first.path.clone()).await.unwrap();
+        assert_eq!(count_rows(&again).await, 1);
+        let other = shared_connection(second.path.clone()).await.unwrap();
+        assert_eq!(count_rows(&other).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_range_parts_assemble_into_a_full_record() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let body = "0123456789";
+            let range = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|range| range.split_once('-'))
+                .map(|(first, last)| (first.parse::<usize>().unwrap(), last.parse::<usize>().unwrap()));
+            let Some((first, last)) = range else {
+                return http_response("200 OK", body);
+            };
+            let part = &body[first..=last];
+            format!(
+                "HTTP/1.1 206 Partial Content\r\nETag: \"v1\"\r\nContent-Range: bytes {first}-{last}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{part}",
+                body.len(),
+                part.len()
+            )
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let part = cache.get_range(&url, 0..4).await.unwrap();
+        assert_eq!((part.status, part.response.as_str()), (206, "0123"));
+        // one part isn't the whole body, so nothing is stored as the url yet
+        assert_eq!(count_rows(cache.connection()).await, 0);
+        let part = cache.get_range(&url, 4..10).await.unwrap();
+        assert_eq!((part.status, part.response.as_str()), (206, "456789"));
+        let full = cache.get(&url).await.unwrap();
+        assert!(full.cached);
+        assert_eq!((full.status, full.response.as_str()), (200, "0123456789"));
+        // ranges of the assembled record are served without a request
+        let part = cache.get_range(&url, 2..5).await.unwrap();
+        assert!(part.cached);
+        assert_eq!((part.status, part.response.as_str()), (206, "234"));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        let parts: i64 = cache
+            .connection()
+            .conn(|conn| conn.query_row("SELECT COUNT(*) FROM ranges", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(parts, 0);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_replays_the_stored_request() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            let accept = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("accept: "))
+                .unwrap_or_default();
+            let body = format!("{accept} {hit}");
+            format!(
+                "HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        })
+        .await;
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let cache = RequestCache::builder()
+            .in_memory()
+            .clock(clock.clone())
+            .build()
+            .await
+            .unwrap();
+        let accept = vec![("Accept".to_string(), "text/plain".to_string())];
+        let resp = cache
+            .request_with_headers("GET", &url, accept.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.response, "text/plain 0");
+        clock.advance(Duration::from_secs(60));
+        // sent with the stored Accept, so the stored variant is the one replaced
+        let refreshed = cache.refresh("GET", &url).await.unwrap();
+        assert_eq!(refreshed.response, "text/plain 1");
+        assert_eq!(refreshed.expires, resp.expires + 60_000);
+        let resp = cache
+            .request_with_headers("GET", &url, accept)
+            .await
+            .unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.response, "text/plain 1");
+        assert_eq!(count_rows(cache.connection()).await, 1);
+        // with nothing stored it's an ordinary fetch
+        let resp = cache.refresh("GET", &format!("{url}/new")).await.unwrap();
+        assert_eq!(resp.response, "*/* 2");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_if_older_than() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("version {hit}"))
+        })
+        .await;
+        let clock = Arc::new(MockClock::new(now_millis()));
+        let cache = RequestCache::builder()
+            .in_memory()
+            .clock(clock.clone())
+            .build()
+            .await
+            .unwrap();
+        cache.get(&url).await.unwrap();
+        clock.advance(Duration::from_secs(10));
+        let max_age = Duration::from_secs(30);
+        let resp = cache.refresh_if_older_than("GET", &url, max_age);
+        let resp = resp.await.unwrap();
+        assert!(resp.cached && resp.response == "version 0");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // still unexpired, but older than the threshold
+        clock.advance(Duration::from_secs(30));
+        let resp = cache.refresh_if_older_than("GET", &url, max_age);
+        let resp = resp.await.unwrap();
+        assert!(!resp.cached && resp.response == "version 1");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.get(&url).await.unwrap().response, "version 1");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_or_stale() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        // the first fetch succeeds, every later one gets a response that isn't HTTP
+        let url = mock_server(move |_| match counted.fetch_add(1, Ordering::SeqCst) {
+            0 => http_response("200 OK", "first"),
+            _ => "not http\r\n\r\n".to_string(),
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let refresh = || cache.request_with_mode("GET", &url, CacheMode::RefreshOrStale);
+        let resp = refresh().await.unwrap();
+        assert_eq!(resp.response, "first");
+        assert!(!resp.stale);
+        // the refresh fails, so the stored record comes back marked stale
+        let resp = refresh().await.unwrap();
+        assert_eq!(resp.response, "first");
+        assert!(resp.stale && resp.cached);
+        assert!(cache
+            .request_with_mode("GET", &url, CacheMode::NoCache)
+            .await
+            .is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_lenient_storage() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
+        .await;
+        let unwritable = "test_lenient_missing_dir/cache.db";
+        let strict = RequestCache::builder().db_path(unwritable).build().await;
+        assert!(matches!(strict, Err(CacheError::Storage(_))));
+        let cache = RequestCache::builder()
+            .db_path(unwritable)
+            .lenient_storage(true)
+            .build()
+            .await
+            .unwrap();
+        // every request goes to the network, and nothing can be served without it
+        for hit in 0..2 {
+            let resp = cache.get(&url).await.unwrap();
+            assert_eq!(resp.response, format!("fetch {hit}"));
+            assert!(!resp.cached && resp.changed.is_none());
+            assert!(resp.storage_failed);
+        }
+        let cached = cache.request_with_mode("GET", &url, CacheMode::OnlyIfCached);
+        assert!(matches!(cached.await, Err(CacheError::NotCached)));
+        // a database that stops taking writes still returns the fetched record
+        let cache = RequestCache::builder()
+            .in_memory()
+            .lenient_storage(true)
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get(&format!("{url}/stored")).await.unwrap();
+        assert!(!resp.storage_failed && resp.changed.is_some());
+        let dropped = cache
+            .connection()
+            .conn(|conn| conn.execute_batch("DROP TABLE requests;"));
+        dropped.await.unwrap();
+        let resp = cache.get(&url).await.unwrap();
+        assert_eq!(resp.response, "fetch 3");
+        assert!(resp.storage_failed && resp.changed.is_none());
+        // lookups failing are misses, so the network is used every time
+        assert!(!cache.get(&url).await.unwrap().cached);
+        let put = cache.put(test_record("http://a.test", "ok")).await;
+        assert!(matches!(put, Err(CacheError::NotStored(_))));
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_read_connections() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", "pooled")
+        })
+        .await;
+        let clean = TestCleanup {
+            path: "test_read_connections".to_string(),
+        };
+        let cache = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .read_connections(3)
+            .build()
+            .await
+            .unwrap();
+        // a record written on the one write connection is found on every reader
+        assert!(!cache.get(&url).await.unwrap().cached);
+        let gets = (0..6).map(|_| cache.get(&url));
+        for resp in futures_util::future::join_all(gets).await {
+            assert!(resp.unwrap().cached);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // hits are still served while the write connection is busy
+        let (release, held) = std::sync::mpsc::channel::<()>();
+        let writer = cache.connection().clone();
+        let busy = tokio::spawn(async move {
+            let held = writer.conn(move |_| {
+                let _ = held.recv();
+                Ok(())
+            });
+            held.await
+        });
+        assert_eq!(cache.get(&url).await.unwrap().response, "pooled");
+        release.send(()).unwrap();
+        busy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_many() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |raw| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            let path = raw.split(' ').nth(1).unwrap_or_default();
+            http_response("200 OK", &format!("{path} {hit}"))
+        })
+        .await;
+        let db_client = create_memory_connection().await.unwrap();
+        put(&db_client, test_record(&format!("{url}/cached"), "stored"))
+            .await
+            .unwrap();
+        let mut reqs: Vec<_> = ["/0", "/cached", "/1", "/2"]
+            .iter()
+            .map(|path| RequestSpec::new(format!("{url}{path}"), 60))
+            .collect();
+        reqs.insert(2, RequestSpec::new("http://127.0.0.1:1/", 60));
+        reqs.push(RequestSpec {
+            method: "NOT A METHOD".to_string(),
+            ..RequestSpec::new(format!("{url}/bad"), 60)
+        });
+        let results = request_many(&db_client, reqs, 2).await;
+        assert_eq!(results.len(), 6);
+        let bodies: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().ok().map(|record| record.response.as_str()))
+            .collect();
+        // each result is where its request was, whatever order they finished in
+        assert!(bodies[0].unwrap().starts_with("/0 "));
+        assert_eq!(bodies[1], Some("stored"));
+        assert!(matches!(results[2], Err(CacheError::Http(_))));
+        assert!(bodies[3].unwrap().starts_with("/1 "));
+        assert!(bodies[4].unwrap().starts_with("/2 "));
+        assert!(matches!(results[5], Err(CacheError::InvalidMethod(_))));
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_should_cache() {
+        let url = mock_server(|raw| {
+            let path = raw.split(' ').nth(1).unwrap_or_default();
+            let body = if path == "/pending" {
+                r#"{"status":"pending"}"#
+            } else {
+                r#"{"status":"done"}"#
+            };
+            http_response("200 OK", body)
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .should_cache(|record| !record.response.contains("pending"))
+            .build()
+            .await
+            .unwrap();
+        // a rejected record is still returned, it just isn't stored
+        let pending = cache.get(&format!("{url}/pending")).await.unwrap();
+        assert_eq!(pending.response, r#"{"status":"pending"}"#);
+        assert!(!pending.cached && pending.changed.is_none());
+        assert_eq!(count_rows(cache.connection()).await, 0);
+        let stream = cache.get_stream(&format!("{url}/pending")).await.unwrap();
+        stream.1.bytes().await.unwrap();
+        assert_eq!(count_rows(cache.connection()).await, 0);
+        let done = cache.get(&format!("{url}/done")).await.unwrap();
+        assert_eq!(done.changed, Some(true));
+        assert_eq!(count_rows(cache.connection()).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_jitter() {
+        let url = mock_server(|_| http_response("200 OK", "warm")).await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .default_timeout(1000)
+            .expiry_jitter(0.1)
+            .clock(Arc::new(MockClock::new(1_000_000)))
+            .build()
+            .await
+            .unwrap();
+        let mut expiries = Vec::new();
+        for i in 0..20 {
+            let resp = cache.get(&format!("{url}/{i}")).await.unwrap();
+            assert!((1_900_000..=2_100_000).contains(&resp.expires));
+            expiries.push(resp.expires);
+        }
+        expiries.sort();
+        expiries.dedup();
+        assert_eq!(expiries.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_body_transforms() {
+        let wire = "{\n  \"name\": \"cache\",\n  \"sizes\": [1, 2, 3]\n}";
+        let url = mock_server(move |_| http_response("200 OK", wire)).await;
+        let reencode = |pretty: bool| {
+            move |body: &[u8]| {
+                let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+                if pretty {
+                    serde_json::to_vec_pretty(&json).unwrap()
+                } else {
+                    serde_json::to_vec(&json).unwrap()
+                }
+            }
         };
-        let db_client = create_connection(clean.path.clone()).await;
-        let resp = request(
-            &db_client,
-            "http://example.com".to_string(),
-            "GET".to_string(),
-            10000,
-            Some(false),
-            None,
-        )
+        let cache = RequestCache::builder()
+            .in_memory()
+            .transform_store(reencode(false))
+            .transform_load(reencode(true))
+            .build()
+            .await
+            .unwrap();
+        let fetched = cache.get(&url).await.unwrap();
+        assert_eq!(fetched.response, wire);
+        let query = "SELECT response_bytes FROM requests;";
+        let stored: Vec<u8> = cache
+            .connection()
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(stored, br#"{"name":"cache","sizes":[1,2,3]}"#);
+        assert!(stored.len() < wire.len());
+        let cached = cache.get(&url).await.unwrap();
+        assert!(cached.cached);
+        let parse = |body: &[u8]| serde_json::from_slice::<serde_json::Value>(body).unwrap();
+        assert_eq!(parse(&cached.response_bytes), parse(wire.as_bytes()));
+        assert_eq!(cached.response.as_bytes(), &cached.response_bytes[..]);
+        let (_, stream) = cache.get_stream(&url).await.unwrap();
+        assert_eq!(stream.bytes().await.unwrap(), cached.response_bytes);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_storage_strategy_per_content_type() {
+        let wire = "{\n  \"name\": \"cache\",\n  \"sizes\": [1, 2, 3]\n}";
+        let url = mock_server(move |raw| {
+            let content_type = match raw.split(' ').nth(1).unwrap_or_default() {
+                "/json" => "application/json; charset=utf-8",
+                _ => "text/plain",
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{wire}",
+                wire.len()
+            )
+        })
         .await;
-        assert!(resp.cached == Some(false));
-        let query = "SELECT COUNT(*) FROM requests";
-        let res = db_client
-            .conn(move |conn| conn.query_row(&query, [], |row| Ok(row.get(0))))
+        let cache = RequestCache::builder()
+            .in_memory()
+            .store_content_type("application/json", StorageStrategy::MinifiedJson)
+            .build()
             .await
             .unwrap();
-        assert!(res == Ok(1));
-        let resp = request(
-            &db_client,
-            "http://example.com".to_string(),
-            "GET".to_string(),
-            10000,
-            None,
-            None,
-        )
+        for path in ["json", "text"] {
+            let fetched = cache.get(&format!("{url}/{path}")).await.unwrap();
+            // the record returned for the fetch keeps the body as received
+            assert_eq!(fetched.response, wire);
+        }
+        let stored = |path: &str| {
+            let url = format!("{url}/{path}");
+            let query = "SELECT response_bytes FROM requests WHERE request = ?1;";
+            cache
+                .connection()
+                .conn(move |conn| conn.query_row(query, [url], |row| row.get::<_, Vec<u8>>(0)))
+        };
+        let minified = br#"{"name":"cache","sizes":[1,2,3]}"#;
+        assert_eq!(stored("json").await.unwrap(), minified);
+        assert_eq!(stored("text").await.unwrap(), wire.as_bytes());
+        let cached = cache.get(&format!("{url}/json")).await.unwrap();
+        assert!(cached.cached);
+        assert_eq!(cached.response_bytes, minified);
+    }
+
+    #[tokio::test]
+    async fn test_cacheable_statuses() {
+        let url = mock_server(|raw| {
+            if raw.starts_with("GET /accepted") {
+                http_response("202 Accepted", "queued")
+            } else {
+                http_response("500 Internal Server Error", "failed")
+            }
+        })
         .await;
-        assert!(resp.cached == Some(true));
-        let query = "SELECT COUNT(*) FROM requests";
-        let res = db_client
-            .conn(move |conn| conn.query_row(&query, [], |row| Ok(row.get(0))))
+        let cache = RequestCache::builder()
+            .in_memory()
+            .cacheable_statuses(|status| matches!(status, 200 | 202))
+            .build()
             .await
             .unwrap();
-        assert!(res == Ok(1));
-        let resp = request(
+        for cached in [false, true] {
+            let resp = cache.get(&format!("{url}/accepted")).await.unwrap();
+            assert_eq!((resp.status, resp.cached), (202, cached));
+        }
+        for _ in 0..2 {
+            let resp = cache.get(&format!("{url}/broken")).await.unwrap();
+            assert_eq!((resp.status, resp.cached), (500, false));
+        }
+        assert_eq!(count_rows(cache.connection()).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_len_and_size() {
+        let db_client = create_memory_connection().await.unwrap();
+        assert_eq!(cache_len(&db_client).await.unwrap(), 0);
+        for i in 0..3 {
+            let record = test_record(&format!("http://a.test/{i}"), &"x".repeat(10_000));
+            put(&db_client, record).await.unwrap();
+        }
+        assert_eq!(cache_len(&db_client).await.unwrap(), 3);
+        assert!(cache_size_bytes(&db_client).await.unwrap() > 30_000);
+    }
+
+    #[tokio::test]
+    async fn test_storage_info_includes_wal() {
+        let clean = TestCleanup {
+            path: "test_storage_info_includes_wal".to_string(),
+        };
+        let db_client = create_connection(clean.path.clone()).await.unwrap();
+        for i in 0..3 {
+            let record = test_record(&format!("http://a.test/{i}"), &"x".repeat(10_000));
+            put(&db_client, record).await.unwrap();
+        }
+        let info = storage_info(&db_client).await.unwrap();
+        assert!(info.wal_bytes.unwrap() > 0);
+        assert!(info.shm_bytes.is_some());
+        assert_eq!(
+            info.total_bytes(),
+            info.database_bytes + info.wal_bytes.unwrap() + info.shm_bytes.unwrap()
+        );
+        let memory = create_memory_connection().await.unwrap();
+        let info = storage_info(&memory).await.unwrap();
+        assert_eq!((info.wal_bytes, info.shm_bytes), (None, None));
+        assert_eq!(info.total_bytes(), info.database_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        // nothing listens on port 1, so any request that reached the network would fail
+        // with CacheError::Http rather than NotCached
+        let (fresh, expired) = ("http://127.0.0.1:1/fresh", "http://127.0.0.1:1/expired");
+        let missing = "http://127.0.0.1:1/missing";
+        let cache = RequestCache::builder()
+            .in_memory()
+            .offline(true)
+            .build()
+            .await
+            .unwrap();
+        put(cache.connection(), test_record(fresh, "fresh"))
+            .await
+            .unwrap();
+        let record = Record {
+            expires: 1,
+            ..test_record(expired, "expired")
+        };
+        put(cache.connection(), record).await.unwrap();
+        assert_eq!(cache.get(fresh).await.unwrap().response, "fresh");
+        let resp = cache.get(expired).await.unwrap();
+        assert!(resp.response == "expired" && resp.stale);
+        for miss in [
+            cache.get(missing).await,
+            cache.refresh("GET", fresh).await,
+            cache.post(fresh, "").await,
+            cache.get_stream(missing).await.map(|(record, _)| record),
+            // expired records can be left out too
+            cache
+                .request_with_mode("GET", expired, CacheMode::OnlyIfFresh)
+                .await,
+        ] {
+            assert!(matches!(miss, Err(CacheError::NotCached)));
+        }
+        let resp = cache.request_with_mode("GET", fresh, CacheMode::OnlyIfFresh);
+        assert_eq!(resp.await.unwrap().response, "fresh");
+        assert_eq!(cache.stats().misses, 0);
+    }
+
+    #[tokio::test]
+    async fn test_touch() {
+        let db_client = create_memory_connection().await.unwrap();
+        let url = "http://a.test/".to_string();
+        let mut record = test_record(&url, "still valid");
+        record.expires = now_millis() + 1_000;
+        put(&db_client, record).await.unwrap();
+        let touched = touch(&db_client, url.clone(), "get".to_string(), 3600);
+        assert!(touched.await.unwrap());
+        let cached = get_cached(&db_client, url.clone(), "GET".to_string());
+        let remaining = cached.await.unwrap().ttl_remaining_secs();
+        assert!((3590..=3600).contains(&remaining));
+        let missing = touch(
             &db_client,
-            "http://example.com".to_string(),
+            "http://b.test/".to_string(),
             "GET".to_string(),
-            10000,
-            Some(true),
-            Some("dummy".to_string()),
-        )
+            60,
+        );
+        assert!(!missing.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
         .await;
-        assert!(resp.cached == Some(false));
+        let db_client = create_memory_connection().await.unwrap();
+        let get = |path: &str| {
+            let url = format!("{url}{path}");
+            let cached = request(&db_client, url, "GET".to_string(), 60, None, None);
+            async move { cached.await.unwrap().response }
+        };
+        assert_eq!(get("/a").await, "fetch 0");
+        assert_eq!(get("/a").await, "fetch 0");
+        let head = Record {
+            method: "HEAD".to_string(),
+            ..test_record(&format!("{url}/a"), "")
+        };
+        put(&db_client, head.clone()).await.unwrap();
+        let removed = invalidate(&db_client, format!("{url}/a"), " get ".to_string());
+        assert_eq!(removed.await.unwrap(), 1);
+        // the next request is a miss, the HEAD record is left alone
+        assert_eq!(get("/a").await, "fetch 1");
+        assert_eq!(count_rows(&db_client).await, 2);
+        assert_eq!(
+            invalidate_url(&db_client, format!("{url}/a"))
+                .await
+                .unwrap(),
+            2
+        );
+        for path in ["/users/1", "/users/2", "/users_x", "/other"] {
+            put(&db_client, test_record(&format!("{url}{path}"), ""))
+                .await
+                .unwrap();
+        }
+        let removed = invalidate_prefix(&db_client, format!("{url}/users/"));
+        assert_eq!(removed.await.unwrap(), 2);
+        let removed = invalidate_prefix(&db_client, format!("{url}/users%"));
+        assert_eq!(removed.await.unwrap(), 0);
+        assert_eq!(count_rows(&db_client).await, 2);
     }
 
     #[tokio::test]
-    async fn test_cache_request_timeout() {
+    async fn test_compressed_responses_stored_decoded() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server_bytes(move |raw| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            // the encodings reqwest can decode are advertised on every request
+            let accepted = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("accept-encoding: "))
+                .unwrap_or_default();
+            assert!(accepted.contains("gzip") && accepted.contains("br"));
+            let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+            gzip.write_all(b"plain text").unwrap();
+            let body = gzip.finish().unwrap();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nVary: Accept-Encoding\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            [head.into_bytes(), body].concat()
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let resp = cache.get(&url).await.unwrap();
+        assert_eq!(resp.response_bytes, b"plain text");
+        // the body is stored decoded, so reading it back needs no decompression
+        let query = "SELECT response_bytes FROM requests;";
+        let stored: Vec<u8> = cache
+            .connection()
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(stored, b"plain text");
+        // every request accepts the same encodings, so Vary on them still hits
+        let resp = cache.get(&url).await.unwrap();
+        assert!(resp.response == "plain text" && resp.cached);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_responses_stored_verbatim() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(b"plain text").unwrap();
+        let compressed = gzip.finish().unwrap();
+        let body = compressed.clone();
+        let url = mock_server_bytes(move |raw| {
+            // the client doesn't decompress, but still asks for what decoded_text can
+            let accepted = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("accept-encoding: "))
+                .unwrap_or_default();
+            assert_eq!(accepted, "gzip, deflate, br, zstd");
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            [head.into_bytes(), body.clone()].concat()
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .store_compressed(true)
+            .build()
+            .await
+            .unwrap();
+        let resp = cache.get(&url).await.unwrap();
+        assert_eq!(resp.response_bytes, compressed);
+        assert_eq!(resp.decoded_text().as_deref(), Some("plain text"));
+        let query = "SELECT response_bytes FROM requests;";
+        let stored: Vec<u8> = cache
+            .connection()
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(stored, compressed);
+        // decoded again on each read, from the stored headers
+        let resp = cache.get(&url).await.unwrap();
+        assert!(resp.cached);
+        assert_eq!(resp.header("content-encoding"), Some("gzip"));
+        assert_eq!(resp.decoded_text().as_deref(), Some("plain text"));
+        // an uncompressed body is text as it is, an unknown encoding isn't decoded
+        let mut record = test_record(&url, "plain text");
+        assert_eq!(record.decoded_text().as_deref(), Some("plain text"));
+        record.headers = vec![("Content-Encoding".to_string(), "compress".to_string())];
+        assert_eq!(record.decoded_text(), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_defaults_the_method() {
+        let url = mock_server(|raw| {
+            let method = raw.split(' ').next().unwrap_or_default();
+            http_response("200 OK", method)
+        })
+        .await;
+        let db_client = create_memory_connection().await.unwrap();
+        assert!(get_if_cached(&db_client, url.clone()).await.is_none());
+        let resp = get(&db_client, url.clone(), 60).await.unwrap();
+        assert!(resp.response == "GET" && !resp.cached);
+        assert!(get(&db_client, url.clone(), 60).await.unwrap().cached);
+        let cached = get_if_cached(&db_client, url).await.unwrap();
+        assert_eq!(cached.method, "GET");
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_tag() {
+        let db_client = create_memory_connection().await.unwrap();
+        let tagged = |url: &str| Record {
+            tag: Some("tenant-1".to_string()),
+            ..test_record(url, "")
+        };
+        put(&db_client, tagged("http://a.test/users"))
+            .await
+            .unwrap();
+        put(&db_client, tagged("http://b.test/orders?tenant=1"))
+            .await
+            .unwrap();
+        put(&db_client, test_record("http://a.test/other", ""))
+            .await
+            .unwrap();
+        // storing a record again without a tag keeps the one it had
+        put(&db_client, test_record("http://a.test/users", ""))
+            .await
+            .unwrap();
+        let removed = invalidate_tag(&db_client, "tenant-1".to_string());
+        assert_eq!(removed.await.unwrap(), 2);
+        assert_eq!(count_rows(&db_client).await, 1);
+        assert!(get_if_cached(&db_client, "http://a.test/other".to_string())
+            .await
+            .is_some());
+
+        let url = mock_server(|raw| {
+            let path = raw.split(' ').nth(1).unwrap_or_default();
+            http_response("200 OK", path)
+        })
+        .await;
+        let cache = RequestCache::builder()
+            .in_memory()
+            .table_name("tagged")
+            .build()
+            .await
+            .unwrap();
+        let resp = cache
+            .request_tagged("GET", &format!("{url}/a"), "group")
+            .await;
+        assert_eq!(resp.unwrap().tag.as_deref(), Some("group"));
+        let hit = cache.get(&format!("{url}/a")).await.unwrap();
+        assert!(hit.cached && hit.tag.as_deref() == Some("group"));
+        cache.get(&format!("{url}/b")).await.unwrap();
+        assert_eq!(cache.invalidate_tag("group").await.unwrap(), 1);
+        let resp = cache.get(&format!("{url}/a")).await.unwrap();
+        assert!(!resp.cached && resp.tag.is_none());
+        assert!(cache.get(&format!("{url}/b")).await.unwrap().cached);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_overrides_dns() {
+        let url = mock_server(|raw| {
+            let host = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("host: "))
+                .unwrap_or_default();
+            http_response("200 OK", host)
+        })
+        .await;
+        let addr: std::net::SocketAddr = url.trim_start_matches("http://").parse().unwrap();
+        // the name doesn't exist, so only the override can reach the mock
+        let cache = RequestCache::builder()
+            .in_memory()
+            .resolve("api.request-cache.invalid", addr)
+            .build()
+            .await
+            .unwrap();
+        let resolved = format!("http://api.request-cache.invalid:{}/", addr.port());
+        let resp = cache.get(&resolved).await.unwrap();
+        assert_eq!(
+            resp.response,
+            format!("api.request-cache.invalid:{}", addr.port())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_savings_report() {
+        let body = "x".repeat(1000);
+        let url = mock_server(move |_| {
+            std::thread::sleep(Duration::from_millis(50));
+            http_response("200 OK", &body)
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        assert_eq!(cache.savings_report(), SavingsReport::default());
+        assert!(!cache.get(&url).await.unwrap().cached);
+        for _ in 0..3 {
+            assert!(cache.get(&url).await.unwrap().cached);
+        }
+        let report = cache.savings_report();
+        assert_eq!(report.bytes_fetched, 1000);
+        assert_eq!(report.bytes_saved, 3000);
+        // each hit saved about the one fetch's 50ms
+        assert!(report.time_saved >= Duration::from_millis(150));
+        cache.refresh("GET", &url).await.unwrap();
+        assert_eq!(cache.savings_report().bytes_fetched, 2000);
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_metrics() {
+        let url = mock_server(|_| http_response("200 OK", "metered")).await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        cache.get(&url).await.unwrap();
+        cache.get(&url).await.unwrap();
+        let _ = cache.get("http://127.0.0.1:1/").await;
+        let metrics = cache.prometheus_metrics();
+        for line in [
+            "# HELP request_cache_hits_total Requests served from the cache.",
+            "# TYPE request_cache_hits_total counter",
+            "request_cache_hits_total 1",
+            "request_cache_misses_total 1",
+            "request_cache_errors_total 1",
+            "request_cache_saved_bytes_total 7",
+            "request_cache_fetched_bytes_total 7",
+            "# TYPE request_cache_entries gauge",
+            "request_cache_entries 1",
+        ] {
+            assert!(metrics.lines().any(|metric| metric == line), "{line}");
+        }
+        assert!(metrics.ends_with('\n'));
+        // the entry count follows this cache's writes without reading the table
+        let metrics = || cache.prometheus_metrics();
+        cache.get(&format!("{url}/other")).await.unwrap();
+        assert!(metrics().contains("request_cache_entries 2\n"));
+        cache.invalidate("GET", &url).await.unwrap();
+        assert!(metrics().contains("request_cache_entries 1\n"));
+        cache.clear().await.unwrap();
+        assert!(metrics().contains("request_cache_entries 0\n"));
+        // and evictions
+        let capped = RequestCache::builder()
+            .in_memory()
+            .max_entries(1)
+            .build()
+            .await
+            .unwrap();
+        capped.put(test_record("http://a.test", "a")).await.unwrap();
+        capped.put(test_record("http://b.test", "b")).await.unwrap();
+        assert!(capped
+            .prometheus_metrics()
+            .contains("request_cache_entries 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_duration() {
+        let url = mock_server(|_| {
+            std::thread::sleep(Duration::from_millis(50));
+            http_response("200 OK", "slow")
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        let miss = cache.get(&url).await.unwrap();
+        assert!(miss.fetch_duration_ms.is_some_and(|ms| ms >= 50));
+        // hits didn't fetch, and the duration isn't stored with the record
+        let hit = cache.get(&url).await.unwrap();
+        assert!(hit.cached && hit.fetch_duration_ms.is_none());
+        let (streamed, body) = cache.get_stream(&format!("{url}/streamed")).await.unwrap();
+        assert!(streamed.fetch_duration_ms.is_some_and(|ms| ms >= 50));
+        assert_eq!(body.bytes().await.unwrap(), b"slow");
+    }
+
+    #[tokio::test]
+    async fn test_page_cache_pragmas() {
         let clean = TestCleanup {
-            path: "test_4".to_string(),
+            path: "test_page_cache_pragmas".to_string(),
         };
-        let db_client = create_connection(clean.path.clone()).await;
-        let resp = request(
-            &db_client,
-            "http://example.com".to_string(),
-            "GET".to_string(),
-            1,
-            Some(false),
-            Some("dummy".to_string()),
+        let pragma = |connection: &Client, name: &'static str| {
+            let query = format!("PRAGMA {name};");
+            let connection = connection.clone();
+            async move {
+                let value = connection
+                    .conn(move |conn| conn.query_row(&query, [], |row| row.get::<_, i64>(0)));
+                value.await.unwrap()
+            }
+        };
+        let cache = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .build()
+            .await
+            .unwrap();
+        // sqlite's defaults are left alone
+        assert_eq!(pragma(cache.connection(), "cache_size").await, -2000);
+        assert_eq!(pragma(cache.connection(), "mmap_size").await, 0);
+        let cache = RequestCache::builder()
+            .db_path(clean.path.clone())
+            .sqlite_cache_size(-64 * 1024)
+            .mmap_size(256 * 1024 * 1024)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(pragma(cache.connection(), "cache_size").await, -64 * 1024);
+        assert_eq!(
+            pragma(cache.connection(), "mmap_size").await,
+            256 * 1024 * 1024
         );
-        assert!(resp.await.cached == Some(false));
-        let query = "SELECT COUNT(*) FROM requests";
-        let res = db_client
-            .conn(move |conn| conn.query_row(&query, [], |row| Ok(row.get(0))))
+    }
+
+    #[tokio::test]
+    async fn test_lookup_tells_stale_from_missing() {
+        let db_client = create_memory_connection().await.unwrap();
+        put(&db_client, test_record("http://a.test/fresh", "fresh"))
             .await
             .unwrap();
-        assert!(res == Ok(1));
-        let resp = request(
-            &db_client,
-            "http://example.com".to_string(),
-            "GET".to_string(),
-            1,
-            Some(false),
-            None,
+        let expired = Record {
+            expires: 1,
+            ..test_record("http://a.test/expired", "expired")
+        };
+        put(&db_client, expired).await.unwrap();
+        let found = |url: &str| lookup(&db_client, url.to_string(), "GET".to_string());
+        assert!(
+            matches!(found("http://a.test/fresh").await, Lookup::Fresh(record) if !record.stale)
         );
-        assert!(resp.await.cached == Some(true));
-        let query = "SELECT COUNT(*) FROM requests";
-        let res = db_client
-            .conn(move |conn| conn.query_row(&query, [], |row| Ok(row.get(0))))
+        assert!(
+            matches!(found("http://a.test/expired").await, Lookup::Stale(record) if record.stale)
+        );
+        assert_eq!(found("http://a.test/missing").await, Lookup::Miss);
+        // invalidated records are stale however long they had left
+        invalidate_before(&db_client, now_millis() + 1)
             .await
             .unwrap();
-        assert!(res == Ok(1));
-        sleep(Duration::from_secs(1));
-        let resp = request(
+        assert!(matches!(
+            found("http://a.test/fresh").await,
+            Lookup::Stale(_)
+        ));
+        // get_cached is the fresh case alone
+        assert!(get_cached(
             &db_client,
-            "http://example.com".to_string(),
-            "GET".to_string(),
-            5,
-            Some(false),
-            None,
-        );
-        assert!(resp.await.cached == Some(false));
-        let query = "SELECT COUNT(*) FROM requests";
-        let res = db_client
-            .conn(move |conn| conn.query_row(&query, [], |row| Ok(row.get(0))))
+            "http://a.test/expired".to_string(),
+            "GET".to_string()
+        )
+        .await
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request_at_logical_time() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            let hit = counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &format!("fetch {hit}"))
+        })
+        .await;
+        let db_client = create_memory_connection().await.unwrap();
+        let at = |now| request_at(&db_client, url.clone(), "GET".to_string(), 60, Some(now));
+        let resp = at(1_000_000).await.unwrap();
+        assert_eq!((resp.fetched_at, resp.expires), (1_000_000, 1_060_000));
+        // expiry follows the given time, not the system's
+        assert!(at(1_059_999).await.unwrap().cached);
+        let cached = get_cached_at(&db_client, url.clone(), "GET".to_string(), Some(1_059_999));
+        assert!(cached.await.is_some());
+        let cached = get_cached_at(&db_client, url.clone(), "GET".to_string(), None);
+        assert!(cached.await.is_none());
+        assert_eq!(at(1_060_000).await.unwrap().response, "fetch 1");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_injected_http_client() {
+        let url = mock_server(|raw| {
+            let marker = raw
+                .lines()
+                .find_map(|line| line.strip_prefix("x-app-client: "))
+                .unwrap_or("none");
+            http_response("200 OK", marker)
+        })
+        .await;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-app-client", "shared".parse().unwrap());
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let cache = RequestCache::builder()
+            .in_memory()
+            .http_client(client)
+            .cookie_store(true)
+            .build()
             .await
             .unwrap();
-        assert!(res == Ok(1));
+        assert_eq!(cache.get(&url).await.unwrap().response, "shared");
+        // cookies are the injected client's business
+        assert!(cache.cookie_jar().is_none());
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        assert_eq!(cache.get(&url).await.unwrap().response, "none");
+    }
+
+    #[tokio::test]
+    async fn test_decode_by_content_type() {
+        let url = mock_server(|raw| {
+            let (content_type, body) = match raw.split(' ').nth(1) {
+                Some("/latin1") => ("text/plain; charset=ISO-8859-1", "cafe"),
+                _ => ("application/json", "{\"a\":1}"),
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        })
+        .await;
+        let cache = RequestCache::builder().in_memory().build().await.unwrap();
+        for (path, content_type) in [
+            ("latin1", "text/plain; charset=ISO-8859-1"),
+            ("json", "application/json"),
+        ] {
+            cache.get(&format!("{url}/{path}")).await.unwrap();
+            // the content type comes back with the stored record
+            let resp = cache.get(&format!("{url}/{path}")).await.unwrap();
+            assert!(resp.cached);
+            assert_eq!(resp.content_type.as_deref(), Some(content_type));
+        }
+        let resp = cache.get(&format!("{url}/json")).await.unwrap();
+        assert_eq!(resp.decode(), Body::Text("{\"a\":1}".into()));
+        let mut record = test_record("http://a.test", "");
+        record.content_type = Some("text/plain; charset=\"iso-8859-1\"".to_string());
+        record.response_bytes = b"caf\xe9".to_vec();
+        assert_eq!(record.decode(), Body::Text("café".into()));
+        record.content_type = Some("image/png".to_string());
+        assert_eq!(record.decode(), Body::Bytes(b"caf\xe9"));
+        record.content_type = None;
+        assert_eq!(record.decode(), Body::Bytes(b"caf\xe9"));
+        record.response_bytes = b"plain".to_vec();
+        assert_eq!(record.decode(), Body::Text("plain".into()));
+    }
+
+    #[tokio::test]
+    async fn test_bytes_stored_once() {
+        let db_client = create_memory_connection().await.unwrap();
+        let mut record = test_record("http://a.test", "");
+        record.content_type = Some("text/plain; charset=ISO-8859-1".to_string());
+        record.response_bytes = b"caf\xe9".to_vec();
+        insert_record(&db_client, DEFAULT_TABLE, record, "", &[])
+            .await
+            .unwrap();
+        // only the bytes are kept, the text is decoded from them
+        let query = "SELECT response IS NULL FROM requests";
+        let text_null: bool = db_client
+            .conn(move |conn| conn.query_row(query, [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert!(text_null);
+        let mut record = db_client
+            .get_record("http://a.test", "GET", "", &[])
+            .await
+            .unwrap();
+        assert_eq!(record.bytes(), b"caf\xe9");
+        assert_eq!(record.text().as_deref(), Some("café"));
+        // bytes that aren't valid in the declared charset are None rather than replaced
+        record.content_type = None;
+        assert!(record.text().is_none());
+    }
+
+    #[derive(Default)]
+    struct MapStore {
+        records: std::sync::Mutex<HashMap<(String, String, String), Record>>,
+    }
+
+    impl CacheStore for MapStore {
+        async fn get_record(
+            &self,
+            url: &str,
+            method: &str,
+            body: &str,
+            headers: &[(String, String)],
+        ) -> Option<Record> {
+            self.get_stale_record(url, method, body, headers)
+                .await
+                .filter(|record| record.expires > now_millis())
+        }
+
+        async fn get_stale_record(
+            &self,
+            url: &str,
+            method: &str,
+            body: &str,
+            _headers: &[(String, String)],
+        ) -> Option<Record> {
+            let key = (url.to_string(), method.to_string(), body.to_string());
+            let record = self.records.lock().unwrap().get(&key).cloned()?;
+            Some(Record {
+                cached: true,
+                changed: None,
+                ..record
+            })
+        }
+
+        async fn insert_record(
+            &self,
+            record: Record,
+            body: &str,
+            _headers: &[(String, String)],
+        ) -> Result<bool, CacheError> {
+            let key = (
+                record.request.clone(),
+                record.method.clone(),
+                body.to_string(),
+            );
+            let old = self.records.lock().unwrap().insert(key, record.clone());
+            Ok(old.map_or(true, |old| old.response_bytes != record.response_bytes))
+        }
+
+        async fn purge_expired(&self) -> Result<usize, CacheError> {
+            let mut records = self.records.lock().unwrap();
+            let before = records.len();
+            records.retain(|_, record| record.expires > now_millis());
+            Ok(before - records.len())
+        }
+
+        async fn delete_record(&self, url: &str, method: &str) -> Result<usize, CacheError> {
+            let mut records = self.records.lock().unwrap();
+            let before = records.len();
+            records
+                .retain(|(request, stored_method, _), _| request != url || stored_method != method);
+            Ok(before - records.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_through_custom_store() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", "from the map")
+        })
+        .await;
+        let store = MapStore::default();
+        for cached in [false, true] {
+            let resp = request(&store, url.clone(), "GET".to_string(), 60, None, None)
+                .await
+                .unwrap();
+            assert_eq!(resp.response, "from the map");
+            assert_eq!(resp.cached, cached);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(get_cached(&store, url, "GET".to_string()).await.is_some());
+        assert_eq!(store.purge_expired().await.unwrap(), 0);
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn test_request_through_redis_store() {
+        // needs a redis to talk to, so runs only where REDIS_URL points at one
+        let Ok(redis_url) = std::env::var("REDIS_URL") else {
+            return;
+        };
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let url = mock_server(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", "from redis")
+        })
+        .await;
+        let store = RedisStore::open(&redis_url).await.unwrap();
+        // a prefix of its own, so runs never see each other's records
+        let store = store.prefix(format!("request_cache_test:{}", now_millis()));
+        for cached in [false, true] {
+            let resp = get(&store, url.clone(), 60).await.unwrap();
+            assert_eq!(resp.response, "from redis");
+            assert_eq!(resp.cached, cached);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let stored = store.get_record(&url, "get", "", &[]).await.unwrap();
+        assert_eq!(stored.bytes(), b"from redis");
+        // an unchanged body isn't reported as changed
+        let mut record = stored.clone();
+        assert!(!store.insert_record(record.clone(), "", &[]).await.unwrap());
+        record.response_bytes = b"changed".to_vec();
+        assert!(store.insert_record(record, "", &[]).await.unwrap());
+        // a record stored already expired is never found, and there's nothing to purge
+        let mut record = test_record(&format!("{url}/expired"), "gone");
+        record.expires = now_millis() - 1;
+        store.insert_record(record, "", &[]).await.unwrap();
+        let expired = format!("{url}/expired");
+        assert!(store
+            .get_stale_record(&expired, "GET", "", &[])
+            .await
+            .is_none());
+        assert_eq!(store.purge_expired().await.unwrap(), 0);
     }
 }
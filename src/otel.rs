@@ -0,0 +1,47 @@
+use opentelemetry::{
+    global::{self, BoxedSpan, BoxedTracer},
+    trace::{Span, SpanKind, Status, Tracer},
+    KeyValue,
+};
+
+use crate::{CacheError, CacheStatus, Record};
+
+// a client span for one request, a child of the caller's current context so it joins
+// their trace; the global provider's tracer is looked up per request, so one installed
+// after the cache is built is still used
+// attributes follow the http semantic conventions, with cache.hit and cache.status for
+// what the cache did
+pub(crate) fn start(tracer: Option<&BoxedTracer>, method: &str, url: &str) -> BoxedSpan {
+    let method = method.to_ascii_uppercase();
+    let attributes = [
+        KeyValue::new("http.request.method", method.clone()),
+        KeyValue::new("url.full", url.to_string()),
+    ];
+    let start = |tracer: &BoxedTracer| {
+        tracer
+            .span_builder(method.clone())
+            .with_kind(SpanKind::Client)
+            .with_attributes(attributes.clone())
+            .start(tracer)
+    };
+    match tracer {
+        Some(tracer) => start(tracer),
+        None => start(&global::tracer("request_cache")),
+    }
+}
+
+pub(crate) fn finish(mut span: BoxedSpan, result: &Result<(Record, CacheStatus), CacheError>) {
+    match result {
+        Ok((record, status)) => {
+            let hit = matches!(status, CacheStatus::Hit | CacheStatus::Stale);
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(record.status),
+            ));
+            span.set_attribute(KeyValue::new("cache.hit", hit));
+            span.set_attribute(KeyValue::new("cache.status", format!("{status:?}")));
+        }
+        Err(err) => span.set_status(Status::error(err.to_string())),
+    }
+    span.end();
+}
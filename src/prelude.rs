@@ -0,0 +1,21 @@
+//! The types most callers need, with the reqwest ones they're used alongside, so those can
+//! be named without depending on reqwest directly or on a version that doesn't match.
+//!
+//! ```
+//! use request_cache::prelude::*;
+//!
+//! async fn fetch(cache: &RequestCache) -> Result<Record, CacheError> {
+//!     let record = cache.request(Method::GET.as_str(), "https://example.com").await?;
+//!     let _ok = StatusCode::from_u16(record.status).ok() == Some(StatusCode::OK);
+//!     let _url = Url::parse(&record.final_url);
+//!     let _headers = HeaderMap::new();
+//!     Ok(record)
+//! }
+//! ```
+
+pub use reqwest::{header::HeaderMap, Method, StatusCode, Url};
+
+pub use crate::{
+    Body, CacheError, CacheMode, CacheStatus, CacheStore, Freshness, PurgeCriteria, Record,
+    RedirectPolicy, RequestCache, RequestCacheBuilder, RequestOptions, RetryPolicy, SqliteStore,
+};
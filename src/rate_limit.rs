@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// a token bucket per host, so requests to a host go out no faster than its limit allows
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    // (host pattern, requests per second), the first matching pattern applies
+    limits: Vec<(String, f64)>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // below zero when requests are already waiting for tokens
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: Vec<(String, f64)>) -> Self {
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) async fn acquire(&self, url: &str) {
+        // wait until a request to url's host is allowed, at once if it has no limit
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase));
+        let Some((host, rate)) = host.and_then(|host| Some((host.clone(), self.rate(&host)?)))
+        else {
+            return;
+        };
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let now = Instant::now();
+            // a full bucket allows a second's worth of requests at once
+            let bucket = buckets.entry(host).or_insert(Bucket {
+                tokens: rate,
+                updated: now,
+            });
+            let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refilled).min(rate);
+            bucket.updated = now;
+            // take the token now, so later requests queue behind this one
+            bucket.tokens -= 1.0;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn rate(&self, host: &str) -> Option<f64> {
+        // patterns are a host, "*.host" for it and its subdomains, or "*" for every host
+        self.limits.iter().find_map(|(pattern, rate)| {
+            let matches = match pattern.strip_prefix("*.") {
+                Some(domain) => {
+                    host == domain
+                        || host
+                            .strip_suffix(domain)
+                            .is_some_and(|sub| sub.ends_with('.'))
+                }
+                None => pattern == "*" || pattern == host,
+            };
+            matches.then_some(*rate)
+        })
+    }
+}
@@ -0,0 +1,260 @@
+use std::{collections::HashMap, time::Duration};
+
+use redis::aio::MultiplexedConnection;
+
+use crate::{
+    body_digest, decode_headers, encode_headers, key_hash, normalize_method, now_millis, vary_key,
+    CacheError, CacheStore, Record,
+};
+
+// a CacheStore in redis, so processes on different hosts share one cache; each record is a
+// hash that redis deletes itself once it's past expires, so there's nothing to purge
+// one variant is kept per request, so a request whose headers named by the response's Vary
+// differ from the stored one's is a miss, and storing its response replaces the other
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: MultiplexedConnection,
+    // put before every key, to keep caches sharing a database apart
+    prefix: String,
+    // how long past expires a record is kept, for revalidating or serving it stale
+    keep_stale_millis: i64,
+}
+
+impl RedisStore {
+    pub async fn open(url: &str) -> Result<Self, CacheError> {
+        // connect to the redis at url, e.g. "redis://127.0.0.1/"
+        let client = redis::Client::open(url).map_err(store_error)?;
+        let connection = client.get_multiplexed_async_connection().await;
+        Ok(Self::new(connection.map_err(store_error)?))
+    }
+
+    pub fn new(connection: MultiplexedConnection) -> Self {
+        RedisStore {
+            connection,
+            prefix: "request_cache".to_string(),
+            keep_stale_millis: 0,
+        }
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn keep_stale(mut self, keep: Duration) -> Self {
+        // expired records are otherwise gone, so can't be revalidated with a conditional
+        // request or served by stale_if_error
+        self.keep_stale_millis = i64::try_from(keep.as_millis()).unwrap_or(i64::MAX);
+        self
+    }
+
+    fn key(&self, url: &str, method: &str, body: &str) -> String {
+        let hash = key_hash(&normalize_method(method), url, body);
+        format!("{}:{hash}", self.prefix)
+    }
+
+    async fn load(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<Record> {
+        // redis failing to answer is a miss, as with sqlite
+        let query = redis::cmd("HGETALL")
+            .arg(self.key(url, method, body))
+            .query_async::<HashMap<String, Vec<u8>>>(&mut self.connection.clone())
+            .await;
+        let fields = match query {
+            Ok(fields) => fields,
+            Err(_err) => {
+                warn!(error = %_err, "couldn't read from redis, treating as a miss");
+                return None;
+            }
+        };
+        let (record, vary) = record_from_hash(fields)?;
+        let requested = vary_key(&record.headers, request_headers);
+        // with the body as sent rather than as keyed, which may include an auth digest
+        let record = Record {
+            body: Some(body.to_string()).filter(|body| !body.is_empty()),
+            ..record
+        };
+        (requested.as_deref() == Some(vary.as_str())).then_some(record)
+    }
+}
+
+impl CacheStore for RedisStore {
+    async fn get_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<Record> {
+        let record = self.load(url, method, body, request_headers).await;
+        record.filter(|record| record.expires > now_millis())
+    }
+
+    async fn get_stale_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<Record> {
+        self.load(url, method, body, request_headers).await
+    }
+
+    async fn insert_record(
+        &self,
+        record: Record,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Result<bool, CacheError> {
+        let record = Record {
+            method: normalize_method(&record.method),
+            ..record
+        };
+        let key = self.key(&record.request, &record.method, body);
+        let vary = vary_key(&record.headers, request_headers).unwrap_or_else(|| "*".to_string());
+        let digest = body_digest(&record.response_bytes);
+        let mut connection = self.connection.clone();
+        let mut stored = redis::cmd("HMGET");
+        stored.arg(&key).arg(&["digest", "vary", "tag"]);
+        let stored =
+            stored.query_async::<(Option<String>, Option<String>, Option<String>)>(&mut connection);
+        let (stored_digest, stored_vary, stored_tag) = stored.await.map_err(store_error)?;
+        let changed = stored_digest != Some(digest.clone()) || stored_vary != Some(vary.clone());
+        // a record keeps its tag when it's replaced by one stored without
+        let tag = record.tag.clone().or(stored_tag);
+        let keep_until = record.expires.saturating_add(self.keep_stale_millis);
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("DEL").arg(&key).ignore();
+        // a record already past keeping would be deleted as it was stored
+        if keep_until > now_millis() {
+            let fields = record_fields(&record, &vary, &digest, tag);
+            pipe.cmd("HSET").arg(&key).arg(fields).ignore();
+            pipe.cmd("PEXPIREAT").arg(&key).arg(keep_until).ignore();
+        }
+        let stored = pipe.query_async::<()>(&mut connection);
+        stored.await.map_err(store_error)?;
+        Ok(changed)
+    }
+
+    async fn purge_expired(&self) -> Result<usize, CacheError> {
+        // redis has already deleted them
+        Ok(0)
+    }
+
+    async fn delete_record(&self, url: &str, method: &str) -> Result<usize, CacheError> {
+        // a key hashes the request body too, so every key under the prefix is scanned for
+        // the ones holding this request
+        let method = normalize_method(method);
+        let mut connection = self.connection.clone();
+        let pattern = format!("{}:*", self.prefix);
+        let (mut cursor, mut deleted) = (0_u64, 0);
+        loop {
+            let mut scan = redis::cmd("SCAN");
+            scan.arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100);
+            let scan = scan.query_async::<(u64, Vec<String>)>(&mut connection);
+            let (next, keys) = scan.await.map_err(store_error)?;
+            for key in keys {
+                let mut stored = redis::cmd("HMGET");
+                stored.arg(&key).arg(&["request", "method"]);
+                let stored =
+                    stored.query_async::<(Option<String>, Option<String>)>(&mut connection);
+                let (request, stored_method) = stored.await.map_err(store_error)?;
+                if request.as_deref() == Some(url) && stored_method.as_ref() == Some(&method) {
+                    let mut removed = redis::cmd("DEL");
+                    removed.arg(&key);
+                    let removed = removed.query_async::<usize>(&mut connection);
+                    deleted += removed.await.map_err(store_error)?;
+                }
+            }
+            if next == 0 {
+                return Ok(deleted);
+            }
+            cursor = next;
+        }
+    }
+}
+
+fn store_error(err: redis::RedisError) -> CacheError {
+    CacheError::Store(Box::new(err))
+}
+
+fn record_fields(
+    record: &Record,
+    vary: &str,
+    digest: &str,
+    tag: Option<String>,
+) -> Vec<(&'static str, Vec<u8>)> {
+    // the hash a record is stored as, leaving out fields that are None
+    let text = |name, value: &str| Some((name, value.as_bytes().to_vec()));
+    let optional = |name, value: &Option<String>| text(name, value.as_deref()?);
+    [
+        text("request", &record.request),
+        text("final_url", &record.final_url),
+        text("method", &record.method),
+        Some(("response_bytes", record.response_bytes.clone())),
+        optional("content_type", &record.content_type),
+        text("status", &record.status.to_string()),
+        text("expires", &record.expires.to_string()),
+        text("fetched_at", &record.fetched_at.to_string()),
+        optional("etag", &record.etag),
+        record.weak_etag.then(|| ("weak_etag", b"1".to_vec())),
+        optional("location", &record.location),
+        text("headers", &encode_headers(&record.headers)),
+        optional("tag", &tag),
+        text("vary", vary),
+        text("digest", digest),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn record_from_hash(mut fields: HashMap<String, Vec<u8>>) -> Option<(Record, String)> {
+    // a cached Record and the vary key it was stored under, None for a key that doesn't
+    // exist, which HGETALL answers with no fields
+    let mut text = |name: &str| {
+        let value = fields.remove(name)?;
+        Some(String::from_utf8_lossy(&value).into_owned())
+    };
+    let request = text("request")?;
+    let record = Record {
+        final_url: text("final_url").unwrap_or_else(|| request.clone()),
+        request,
+        method: text("method")?,
+        body: None,
+        response: String::new(),
+        response_bytes: Vec::new(),
+        content_type: text("content_type"),
+        status: text("status")?.parse().ok()?,
+        expires: text("expires")?.parse().ok()?,
+        cached: true,
+        fetched_at: text("fetched_at")?.parse().ok()?,
+        changed: None,
+        etag: text("etag"),
+        weak_etag: text("weak_etag").is_some(),
+        location: text("location"),
+        headers: decode_headers(text("headers")),
+        stale: false,
+        revalidated: false,
+        tag: text("tag"),
+        fetch_duration_ms: None,
+        storage_failed: false,
+    };
+    let vary = text("vary")?;
+    let response_bytes = fields.remove("response_bytes")?;
+    let record = Record {
+        response: String::from_utf8_lossy(&response_bytes).into_owned(),
+        response_bytes,
+        ..record
+    };
+    Some((record, vary))
+}
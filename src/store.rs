@@ -0,0 +1,703 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_sqlite::Client;
+
+use crate::{
+    body_digest, body_stream::StoredBody, breakdown_by_host_from, breakdown_by_method_from,
+    cache_len_from, canonical_body, clear_cache_from, delete_ranges_from, delete_stored,
+    get_record, get_record_with, insert_record, insert_record_counted, invalidate_before_for,
+    invalidate_from, invalidate_prefix_from, invalidate_tag_from, invalidate_url_from,
+    invalidate_where_body_from, key_body, normalize_method, now_millis, purge_expired_from,
+    purge_where_from, query_record, set_pinned_from, store_range_from, stored_request_from,
+    touch_from, verify_all_from, BodyStream, CacheError, Clock, PurgeCriteria, RangePart, Record,
+    SystemClock, VerifyReport, DEFAULT_TABLE, RECORD_COLUMNS, STREAMED_COLUMNS,
+};
+#[cfg(feature = "json")]
+use crate::{export_json_from, import_json_into};
+
+// where records are kept, so the request logic can run over backends other than sqlite
+// request_headers are the headers a request is sent with, to pick the variant a Vary
+// response header selects
+pub trait CacheStore: Send + Sync {
+    // an unexpired record for the request, None on a miss
+    fn get_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> impl Future<Output = Option<Record>> + Send;
+
+    // the newest record for the request however old, to revalidate or serve on errors
+    fn get_stale_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> impl Future<Output = Option<Record>> + Send;
+
+    // store a record for a request body, returning whether the response differs from the stored one
+    fn insert_record(
+        &self,
+        record: Record,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> impl Future<Output = Result<bool, CacheError>> + Send;
+
+    // delete every expired record, returning how many were removed
+    fn purge_expired(&self) -> impl Future<Output = Result<usize, CacheError>> + Send;
+
+    // delete every stored variant of one request, returning how many records went
+    fn delete_record(
+        &self,
+        url: &str,
+        method: &str,
+    ) -> impl Future<Output = Result<usize, CacheError>> + Send;
+}
+
+// derives the url a record is keyed on from a request's method and url
+pub(crate) type KeyFn = Arc<dyn Fn(&str, &str) -> String + Send + Sync>;
+// rewrites a body on its way into or out of the table
+pub(crate) type TransformFn = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+// how a body is stored, chosen by its response's content type
+#[derive(Clone)]
+pub enum StorageStrategy {
+    // as received
+    Verbatim,
+    // parsed and written back out without whitespace, verbatim if it isn't JSON
+    #[cfg(feature = "json")]
+    MinifiedJson,
+    // rewritten by the function, as transform_store does for every body
+    Transform(TransformFn),
+}
+
+impl StorageStrategy {
+    fn apply(&self, body: &[u8]) -> Option<Vec<u8>> {
+        // the body to store instead, None to store it as it is
+        match self {
+            StorageStrategy::Verbatim => None,
+            #[cfg(feature = "json")]
+            StorageStrategy::MinifiedJson => {
+                let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+                serde_json::to_vec(&value).ok()
+            }
+            StorageStrategy::Transform(transform) => Some(transform(body)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    connection: Client,
+    // more connections to the same database that lookups are spread over, so hits don't
+    // queue behind writes on connection; empty reads on connection too
+    readers: Arc<[Client]>,
+    next_reader: Arc<AtomicUsize>,
+    table: String,
+    // decides which records have expired
+    clock: Arc<dyn Clock>,
+    // whether the Authorization header is part of the cache key
+    auth_in_key: bool,
+    // whether JSON bodies are keyed in canonical form
+    canonical_json: bool,
+    // keys records on something other than the url, None keys on the url itself
+    key_fn: Option<KeyFn>,
+    // whether a failed write is logged and skipped rather than returned
+    lenient: bool,
+    // whether bodies are checked against their digest as they're read
+    verify: bool,
+    // (media type, strategy) for bodies of each content type, applied before transform_store;
+    // other types are stored verbatim
+    strategies: Arc<[(String, StorageStrategy)]>,
+    // applied to bodies as they're stored and loaded, None leaves them as they are
+    transform_store: Option<TransformFn>,
+    transform_load: Option<TransformFn>,
+    // the rows in table, kept up to date by this store's own writes and counted again on
+    // every purge, so it can be read without going to the database
+    entries: Arc<AtomicI64>,
+}
+
+impl SqliteStore {
+    pub fn new(connection: Client) -> Self {
+        // records go in the default table, as with the free functions
+        Self::with_table(connection, DEFAULT_TABLE.to_string(), Arc::new(SystemClock))
+    }
+
+    pub(crate) fn with_table(connection: Client, table: String, clock: Arc<dyn Clock>) -> Self {
+        // the table must already be validated and created
+        SqliteStore {
+            connection,
+            readers: Arc::new([]),
+            next_reader: Arc::new(AtomicUsize::new(0)),
+            table,
+            clock,
+            auth_in_key: false,
+            canonical_json: false,
+            key_fn: None,
+            lenient: false,
+            verify: false,
+            strategies: Arc::new([]),
+            transform_store: None,
+            transform_load: None,
+            entries: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    pub(crate) fn auth_in_key(mut self, enabled: bool) -> Self {
+        self.auth_in_key = enabled;
+        self
+    }
+
+    #[cfg(feature = "json")]
+    pub fn canonical_json(mut self, enabled: bool) -> Self {
+        // key request bodies that are JSON with their keys sorted and whitespace dropped, so
+        // POSTs cached with cache_unsafe whose bodies only differ in formatting share a record;
+        // numbers are keyed as parsed, so ones past f64's precision that differ only in
+        // digits it can't hold share one too
+        self.canonical_json = enabled;
+        self
+    }
+
+    pub(crate) fn key_fn(mut self, key_fn: Option<KeyFn>) -> Self {
+        self.key_fn = key_fn;
+        self
+    }
+
+    pub(crate) fn lenient(mut self, enabled: bool) -> Self {
+        self.lenient = enabled;
+        self
+    }
+
+    pub(crate) fn verify(mut self, enabled: bool) -> Self {
+        self.verify = enabled;
+        self
+    }
+
+    pub(crate) fn transforms(
+        mut self,
+        store: Option<TransformFn>,
+        load: Option<TransformFn>,
+    ) -> Self {
+        self.transform_store = store;
+        self.transform_load = load;
+        self
+    }
+
+    pub(crate) fn strategies(mut self, strategies: Vec<(String, StorageStrategy)>) -> Self {
+        self.strategies = strategies.into();
+        self
+    }
+
+    pub(crate) fn readers(mut self, readers: Vec<Client>) -> Self {
+        self.readers = readers.into();
+        self
+    }
+
+    fn reader(&self) -> &Client {
+        // the next read connection in turn
+        if self.readers.is_empty() {
+            return &self.connection;
+        }
+        let next = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        &self.readers[next % self.readers.len()]
+    }
+
+    pub(crate) async fn count_entries(&self) -> Result<usize, async_sqlite::Error> {
+        // count the rows again, to pick up writes made other than through this store
+        let entries = cache_len_from(&self.connection, &self.table).await?;
+        self.entries.store(entries as i64, Ordering::Relaxed);
+        Ok(entries)
+    }
+
+    pub(crate) fn entries(&self) -> usize {
+        // concurrent writes can leave it briefly behind, never below zero
+        self.entries.load(Ordering::Relaxed).max(0) as usize
+    }
+
+    fn removed<T>(&self, removed: T, count: impl Fn(&T) -> usize) -> T {
+        self.entries
+            .fetch_sub(count(&removed) as i64, Ordering::Relaxed);
+        removed
+    }
+
+    fn key_body(&self, body: &str, request_headers: &[(String, String)]) -> String {
+        let body = canonical_body(body, self.canonical_json);
+        key_body(&body, request_headers, self.auth_in_key)
+    }
+
+    fn key_url(&self, method: &str, url: &str) -> String {
+        match &self.key_fn {
+            Some(key_fn) => key_fn(method, url),
+            None => url.to_string(),
+        }
+    }
+
+    fn requested(&self, record: Option<Record>, url: &str, body: &str) -> Option<Record> {
+        // a record found by a derived key is reported under the url that was asked for, and
+        // with the body as sent rather than as keyed, which may include an auth digest
+        let body = Some(body.to_string()).filter(|body| !body.is_empty());
+        record.map(|record| match self.key_fn {
+            Some(_) => Record {
+                request: url.to_string(),
+                body,
+                ..record
+            },
+            None => Record { body, ..record },
+        })
+    }
+
+    async fn verified(&self, found: Option<(Record, StoredBody)>) -> Option<Record> {
+        // a body that no longer matches the digest it was stored with, e.g. after a bad
+        // sector or an interrupted write, is a miss so it's fetched again rather than served;
+        // the row is deleted, as a refetch of the same body wouldn't rewrite it; a compressed
+        // one that won't decode is already a miss
+        let (record, stored) = found?;
+        let intact = |digest: &String| *digest == body_digest(&record.response_bytes);
+        if !self.verify || stored.digest.as_ref().map_or(true, intact) {
+            return Some(record);
+        }
+        warn!(url = %record.request, "stored body doesn't match its digest, deleting it");
+        if let Ok(deleted) = delete_stored(&self.connection, &self.table, stored).await {
+            self.removed(deleted, |deleted| *deleted);
+        }
+        None
+    }
+
+    fn transformed(record: Record, transform: Option<&TransformFn>) -> Record {
+        // the text is kept in step with the bytes it reads as
+        let Some(transform) = transform else {
+            return record;
+        };
+        let response_bytes = transform(&record.response_bytes);
+        Record {
+            response: String::from_utf8_lossy(&response_bytes).into_owned(),
+            response_bytes,
+            ..record
+        }
+    }
+
+    fn stored_as(&self, record: Record) -> Record {
+        // the record with its body as its content type's strategy stores it
+        let Some(content_type) = &record.content_type else {
+            return record;
+        };
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        let strategy =
+            self.strategies
+                .iter()
+                .find(|(pattern, _)| match pattern.strip_suffix("/*") {
+                    Some(kind) => media_type
+                        .split_once('/')
+                        .is_some_and(|(media_kind, _)| media_kind.eq_ignore_ascii_case(kind)),
+                    None => pattern.eq_ignore_ascii_case(media_type),
+                });
+        let Some(response_bytes) =
+            strategy.and_then(|(_, strategy)| strategy.apply(&record.response_bytes))
+        else {
+            return record;
+        };
+        Record {
+            response: String::from_utf8_lossy(&response_bytes).into_owned(),
+            response_bytes,
+            ..record
+        }
+    }
+
+    pub fn connection(&self) -> &Client {
+        &self.connection
+    }
+
+    pub(crate) async fn invalidate_tag(
+        &self,
+        tag: &str,
+    ) -> Result<Vec<(String, String)>, async_sqlite::Error> {
+        let invalidated = invalidate_tag_from(&self.connection, &self.table, tag.to_string());
+        Ok(self.removed(invalidated.await?, Vec::len))
+    }
+
+    pub(crate) async fn invalidate(
+        &self,
+        method: &str,
+        url: &str,
+    ) -> Result<usize, async_sqlite::Error> {
+        let url = self.key_url(method, url);
+        let invalidated = invalidate_from(&self.connection, &self.table, url, method.to_string());
+        Ok(self.removed(invalidated.await?, |invalidated| *invalidated))
+    }
+
+    pub(crate) async fn store_range(
+        &self,
+        url: &str,
+        part: RangePart,
+    ) -> Result<Vec<RangePart>, async_sqlite::Error> {
+        store_range_from(&self.connection, &self.table, url.to_string(), part).await
+    }
+
+    pub(crate) async fn delete_ranges(&self, url: &str) -> Result<(), async_sqlite::Error> {
+        delete_ranges_from(&self.connection, &self.table, url.to_string()).await
+    }
+
+    pub(crate) async fn stored_request(
+        &self,
+        method: &str,
+        url: &str,
+    ) -> Option<(String, Vec<(String, String)>)> {
+        // the body and the headers named by Vary that the newest record for the request was
+        // fetched with, to send it again; None when nothing is stored or it can't be read
+        let key_url = self.key_url(method, url);
+        let method = normalize_method(method);
+        let stored = stored_request_from(&self.connection, &self.table, key_url, method);
+        let (mut body, vary) = stored.await.ok()??;
+        // a body keyed with a digest of the credentials after it, which aren't stored, is
+        // sent without it
+        if self.auth_in_key {
+            let digest =
+                |line: &str| line.len() == 64 && line.bytes().all(|b| b.is_ascii_hexdigit());
+            if let Some((sent, _)) = body.rsplit_once('\n').filter(|(_, last)| digest(last)) {
+                body.truncate(sent.len());
+            }
+        }
+        // a header the request didn't send is keyed with no value
+        let headers = vary
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Some((body, headers))
+    }
+
+    pub(crate) async fn invalidate_url(
+        &self,
+        url: &str,
+    ) -> Result<Vec<(String, String)>, async_sqlite::Error> {
+        let invalidated = invalidate_url_from(&self.connection, &self.table, url.to_string());
+        Ok(self.removed(invalidated.await?, Vec::len))
+    }
+
+    pub(crate) async fn invalidate_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, async_sqlite::Error> {
+        let invalidated = invalidate_prefix_from(&self.connection, &self.table, prefix.to_string());
+        Ok(self.removed(invalidated.await?, Vec::len))
+    }
+
+    pub(crate) async fn invalidate_where_body(
+        &self,
+        predicate: impl Fn(&str) -> bool,
+    ) -> Result<usize, async_sqlite::Error> {
+        let invalidated = invalidate_where_body_from(&self.connection, &self.table, predicate);
+        Ok(self.removed(invalidated.await?, |invalidated| *invalidated))
+    }
+
+    pub(crate) async fn invalidate_before(
+        &self,
+        timestamp: i64,
+    ) -> Result<(), async_sqlite::Error> {
+        invalidate_before_for(&self.connection, &self.table, timestamp).await
+    }
+
+    pub(crate) async fn touch(
+        &self,
+        method: &str,
+        url: &str,
+        expires: i64,
+    ) -> Result<bool, async_sqlite::Error> {
+        let url = self.key_url(method, url);
+        touch_from(
+            &self.connection,
+            &self.table,
+            url,
+            method.to_string(),
+            expires,
+        )
+        .await
+    }
+
+    pub(crate) async fn set_pinned(
+        &self,
+        method: &str,
+        url: &str,
+        pinned: bool,
+    ) -> Result<bool, async_sqlite::Error> {
+        let url = self.key_url(method, url);
+        set_pinned_from(
+            &self.connection,
+            &self.table,
+            url,
+            method.to_string(),
+            pinned,
+        )
+        .await
+    }
+
+    pub(crate) async fn clear(&self) -> Result<Vec<(String, String)>, async_sqlite::Error> {
+        let cleared = clear_cache_from(&self.connection, &self.table).await?;
+        Ok(self.removed(cleared, Vec::len))
+    }
+
+    pub(crate) async fn verify_all(
+        &self,
+        delete_corrupt: bool,
+    ) -> Result<VerifyReport, async_sqlite::Error> {
+        let report = verify_all_from(&self.connection, &self.table, delete_corrupt).await?;
+        Ok(self.removed(report, |report| report.deleted))
+    }
+
+    pub(crate) async fn len(&self) -> Result<usize, async_sqlite::Error> {
+        cache_len_from(self.reader(), &self.table).await
+    }
+
+    pub(crate) async fn breakdown_by_method(
+        &self,
+    ) -> Result<HashMap<String, i64>, async_sqlite::Error> {
+        breakdown_by_method_from(self.reader(), &self.table).await
+    }
+
+    pub(crate) async fn breakdown_by_host(
+        &self,
+    ) -> Result<HashMap<String, i64>, async_sqlite::Error> {
+        breakdown_by_host_from(self.reader(), &self.table).await
+    }
+
+    #[cfg(feature = "json")]
+    pub(crate) async fn export_json(&self) -> Result<String, CacheError> {
+        export_json_from(&self.connection, &self.table).await
+    }
+
+    #[cfg(feature = "json")]
+    pub(crate) async fn import_json(&self, json: &str) -> Result<usize, CacheError> {
+        let now = self.clock.now_millis();
+        let imported = import_json_into(&self.connection, &self.table, json, now).await?;
+        // an imported record may replace one already stored
+        self.count_entries().await?;
+        Ok(imported)
+    }
+
+    pub(crate) async fn purge_where(
+        &self,
+        criteria: PurgeCriteria,
+    ) -> Result<Vec<(String, String)>, async_sqlite::Error> {
+        let now = self.clock.now_millis();
+        let purged = purge_where_from(&self.connection, &self.table, criteria, now).await?;
+        Ok(self.removed(purged, Vec::len))
+    }
+
+    pub(crate) async fn get_body_stream(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<(Record, BodyStream)> {
+        // as get_record, with the body left in sqlite to be streamed; a load transform or
+        // verification needs the whole body, so then it's loaded and streamed from memory
+        if self.transform_load.is_some() || self.verify {
+            let mut record = self.get_record(url, method, body, request_headers).await?;
+            let bytes = std::mem::take(&mut record.response_bytes);
+            record.response.clear();
+            return Some((record, BodyStream::loaded(bytes)));
+        }
+        let key_url = self.key_url(method, url);
+        let keyed = self.key_body(body, request_headers);
+        let (method, now) = (method.to_string(), self.clock.now_millis());
+        let reader = self.reader();
+        let found = get_record_with(
+            reader,
+            &self.table,
+            key_url,
+            method,
+            keyed,
+            request_headers,
+            now,
+            STREAMED_COLUMNS,
+        );
+        let (record, stored) = found.await?;
+        let stream = BodyStream::stored(reader.clone(), self.table.clone(), stored);
+        Some((self.requested(Some(record), url, body)?, stream))
+    }
+}
+
+impl CacheStore for SqliteStore {
+    async fn get_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<Record> {
+        let key_url = self.key_url(method, url);
+        let keyed = self.key_body(body, request_headers);
+        let found = get_record_with(
+            self.reader(),
+            &self.table,
+            key_url,
+            method.to_string(),
+            keyed,
+            request_headers,
+            self.clock.now_millis(),
+            RECORD_COLUMNS,
+        );
+        let record = self.verified(found.await).await;
+        let record = record.map(|record| Self::transformed(record, self.transform_load.as_ref()));
+        self.requested(record, url, body)
+    }
+
+    async fn get_stale_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<Record> {
+        let key_url = self.key_url(method, url);
+        let keyed = self.key_body(body, request_headers);
+        let found = query_record(
+            self.reader(),
+            &self.table,
+            key_url,
+            method.to_string(),
+            keyed,
+            None,
+            request_headers,
+            RECORD_COLUMNS,
+        );
+        let found = found.await.map(|(record, _, stored)| (record, stored));
+        let record = self.verified(found).await;
+        let record = record.map(|record| Self::transformed(record, self.transform_load.as_ref()));
+        self.requested(record, url, body)
+    }
+
+    async fn insert_record(
+        &self,
+        record: Record,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Result<bool, CacheError> {
+        let body = self.key_body(body, request_headers);
+        let record = Record {
+            request: self.key_url(&record.method, &record.request),
+            ..Self::transformed(self.stored_as(record), self.transform_store.as_ref())
+        };
+        let inserted = insert_record_counted(
+            &self.connection,
+            &self.table,
+            record,
+            &body,
+            request_headers,
+        );
+        match inserted.await {
+            // the response is still returned, flagged as not stored
+            Err(err) if self.lenient => {
+                warn!(error = %err, "couldn't store record, continuing uncached");
+                Err(CacheError::NotStored(Box::new(err.into())))
+            }
+            inserted => {
+                let (changed, added) = inserted?;
+                self.entries.fetch_add(added, Ordering::Relaxed);
+                Ok(changed)
+            }
+        }
+    }
+
+    async fn purge_expired(&self) -> Result<usize, CacheError> {
+        let purged = purge_expired_from(&self.connection, &self.table, self.clock.now_millis());
+        let purged = purged.await?;
+        self.count_entries().await?;
+        Ok(purged)
+    }
+
+    async fn delete_record(&self, url: &str, method: &str) -> Result<usize, CacheError> {
+        Ok(self.invalidate(method, url).await?)
+    }
+}
+
+// a bare connection stores in the default table, so existing callers of the free functions
+// can keep passing one
+impl CacheStore for Client {
+    async fn get_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<Record> {
+        let now = now_millis();
+        sqlite_get(self, DEFAULT_TABLE, url, method, body, request_headers, now).await
+    }
+
+    async fn get_stale_record(
+        &self,
+        url: &str,
+        method: &str,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<Record> {
+        sqlite_get_stale(self, DEFAULT_TABLE, url, method, body, request_headers).await
+    }
+
+    async fn insert_record(
+        &self,
+        record: Record,
+        body: &str,
+        request_headers: &[(String, String)],
+    ) -> Result<bool, CacheError> {
+        Ok(insert_record(self, DEFAULT_TABLE, record, body, request_headers).await?)
+    }
+
+    async fn purge_expired(&self) -> Result<usize, CacheError> {
+        Ok(purge_expired_from(self, DEFAULT_TABLE, now_millis()).await?)
+    }
+
+    async fn delete_record(&self, url: &str, method: &str) -> Result<usize, CacheError> {
+        let (url, method) = (url.to_string(), method.to_string());
+        Ok(invalidate_from(self, DEFAULT_TABLE, url, method).await?)
+    }
+}
+
+async fn sqlite_get(
+    connection: &Client,
+    table: &str,
+    url: &str,
+    method: &str,
+    body: &str,
+    request_headers: &[(String, String)],
+    now: i64,
+) -> Option<Record> {
+    let (url, method, body) = (url.to_string(), method.to_string(), body.to_string());
+    get_record(connection, table, url, method, body, request_headers, now).await
+}
+
+async fn sqlite_get_stale(
+    connection: &Client,
+    table: &str,
+    url: &str,
+    method: &str,
+    body: &str,
+    request_headers: &[(String, String)],
+) -> Option<Record> {
+    let (url, method, body) = (url.to_string(), method.to_string(), body.to_string());
+    let columns = RECORD_COLUMNS;
+    query_record(
+        connection,
+        table,
+        url,
+        method,
+        body,
+        None,
+        request_headers,
+        columns,
+    )
+    .await
+    .map(|(record, _, _)| record)
+}